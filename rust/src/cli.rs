@@ -0,0 +1,409 @@
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::bip32::Xpub;
+use bitcoincore_rpc::bitcoin::hex::{DisplayHex, FromHex};
+use bitcoincore_rpc::bitcoin::{Address, Amount, Denomination, Network, OutPoint, Txid};
+use bitcoincore_rpc::json::AddressType;
+use clap::builder::TypedValueParser;
+use clap::{Parser, Subcommand};
+
+use crate::mining;
+use crate::report::{Format, Unit};
+use std::path::PathBuf;
+
+// Command-line options. Connection flags fall back to the `BITCOIN_RPC_*`
+// environment variables, then to the `--config` file and then to the regtest
+// setup from docker-compose, so running with no arguments behaves exactly as
+// before. Amount, network and output path likewise override the config file.
+#[derive(Parser, Debug)]
+#[command(about = "Miner/Trader transaction demo against a Bitcoin Core node")]
+pub struct Cli {
+    /// Run only one step of the flow; without a subcommand everything runs:
+    /// mine, send, confirm and report
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML file with rpc_url, rpc_user, rpc_pass, amount, network and out
+    /// settings; explicit flags override its values
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Bitcoin Core RPC endpoint, without the `/wallet/<name>` suffix
+    /// [env: BITCOIN_RPC_URL] [default: http://127.0.0.1:18443]
+    #[arg(long)]
+    pub rpc_url: Option<String>,
+
+    /// RPC username [env: BITCOIN_RPC_USER] [default: alice]
+    #[arg(long)]
+    pub rpc_user: Option<String>,
+
+    /// RPC password [env: BITCOIN_RPC_PASS] [default: password]
+    #[arg(long)]
+    pub rpc_pass: Option<String>,
+
+    /// Give up on any single RPC call after this many seconds
+    #[arg(long, value_name = "SECS", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rpc_timeout: u64,
+
+    /// Authenticate with the node's `.cookie` file instead of user/password;
+    /// BITCOIN_RPC_USER and BITCOIN_RPC_PASS must not be set with it
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["rpc_user", "rpc_pass"])]
+    pub cookie_file: Option<PathBuf>,
+
+    /// Network the node runs on: regtest, testnet, signet or bitcoin.
+    /// Mining (and therefore the full demo flow) is only available on regtest.
+    /// [default: regtest]
+    #[arg(long)]
+    pub network: Option<Network>,
+
+    /// Amount to send from Miner to Trader, in BTC [default: 20]
+    #[arg(long, value_name = "BTC", value_parser = parse_btc)]
+    pub amount: Option<Amount>,
+
+    /// Refuse a send whose fee exceeds this many BTC; a --dry-run stops before
+    /// its report is written
+    #[arg(long, value_name = "BTC", value_parser = parse_btc, default_value = "0.01")]
+    pub max_fee: Amount,
+
+    /// Send the Miner's whole spendable balance to the Trader, with the fee
+    /// taken out of it, leaving no change
+    #[arg(long, conflicts_with_all = ["amount", "recipient", "split"])]
+    pub sweep: bool,
+
+    /// Sweep like --sweep, but through Core's sendall RPC (v24.0 and later);
+    /// older nodes fall back to --sweep with a warning
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "amount", "recipient", "split", "sweep", "use_psbt", "op_return", "from_utxo", "locktime"
+        ]
+    )]
+    pub sendall: bool,
+
+    /// Have the Trader bear the fee: it is subtracted from the Trader output,
+    /// which then receives slightly less than --amount
+    #[arg(long, conflicts_with_all = ["sweep", "sendall"])]
+    pub fee_from_trader: bool,
+
+    /// Instead of the single transfer, split --amount into N equal payments to
+    /// fresh Trader addresses, each in its own transaction, and confirm them all
+    /// in one block. Writes no report; the options shaping, confirming or
+    /// checking a single transaction are rejected with it
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = [
+            "dry_run", "no_confirm", "mine_blocks", "confirmations", "use_psbt", "op_return",
+            "locktime", "from_utxo", "bump_fee_rate", "fee_from_trader", "show_raw", "max_fee"
+        ]
+    )]
+    pub split: Option<u32>,
+
+    /// Extra payment to include in the same transaction as the Trader payment,
+    /// as `<ADDRESS>:<AMOUNT_BTC>`. May be given several times.
+    #[arg(long, value_name = "ADDRESS:AMOUNT", value_parser = parse_recipient)]
+    pub recipient: Vec<Recipient>,
+
+    /// Show a progress bar while mining (only when stdout is a terminal)
+    #[arg(long)]
+    pub progress: bool,
+
+    /// Print only the txid on stdout (one per line with --split); balances,
+    /// tables and other output go to the log on stderr instead. out.txt is
+    /// still written.
+    #[arg(long)]
+    pub quiet: bool,
+
+    /// Explicit fee rate for the send, in sat/vB (satoshis per virtual byte of the
+    /// transaction's weight / 4). Must be positive; estimated via estimatesmartfee
+    /// when omitted.
+    #[arg(long, value_name = "SAT_PER_VB", value_parser = parse_fee_rate)]
+    pub fee_rate: Option<f64>,
+
+    /// Send via walletcreatefundedpsbt / walletprocesspsbt / finalizepsbt /
+    /// sendrawtransaction instead of sendtoaddress
+    #[arg(long)]
+    pub use_psbt: bool,
+
+    /// Spend this Miner UTXO as the first input (coin control) instead of
+    /// letting the wallet pick; further inputs are added only if it is too small
+    #[arg(long, value_name = "TXID:VOUT", conflicts_with = "use_psbt")]
+    pub from_utxo: Option<OutPoint>,
+
+    /// Add an OP_RETURN output carrying this hex payload (at most 80 bytes)
+    #[arg(long, value_name = "HEX", value_parser = parse_op_return, conflicts_with = "use_psbt")]
+    pub op_return: Option<String>,
+
+    /// Lock the transaction until this block height (nLockTime with non-final
+    /// sequences). Blocks are mined up to the height before it is broadcast.
+    #[arg(
+        long,
+        value_name = "HEIGHT",
+        value_parser = clap::value_parser!(u32).range(1..500_000_000),
+        conflicts_with = "use_psbt"
+    )]
+    pub locktime: Option<u32>,
+
+    /// Retry the send a few times when it fails with a transient RPC error
+    /// (node warming up, wallet rescanning); other errors still abort
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Build and fund the transaction and report its projected fee and change,
+    /// but never broadcast it or mine a confirmation block
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Create descriptor (`true`) or legacy (`false`) wallets instead of the
+    /// node's default type; existing wallets of the other type are used with a warning
+    #[arg(long, value_name = "BOOL")]
+    pub descriptor: Option<bool>,
+
+    /// After mining, back up the Miner wallet into this directory with
+    /// backupwallet (the node writes the file, so the path is the node's)
+    #[arg(long, value_name = "PATH")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Directory holding the Miner and Trader wallets outside the node's
+    /// default wallet directory; each is loaded (or created) by its path
+    /// <PATH>/<name>. The node must see the same path, e.g. run locally
+    #[arg(long, value_name = "PATH")]
+    pub wallet_dir: Option<PathBuf>,
+
+    /// Passphrase of an encrypted Miner wallet; it is unlocked with
+    /// walletpassphrase for the send and locked again with walletlock afterwards
+    #[arg(long, value_name = "PASSPHRASE")]
+    pub wallet_passphrase: Option<String>,
+
+    /// Rescan the chain for both wallets' transactions after loading them
+    /// (refused on pruned nodes)
+    #[arg(long)]
+    pub rescan: bool,
+
+    /// Block height to start the --rescan from [default: 0]
+    #[arg(long, value_name = "HEIGHT", requires = "rescan")]
+    pub rescan_from: Option<u32>,
+
+    /// Import fixed descriptors (see wallet::MINER_SEED / TRADER_SEED) so the
+    /// mining and Trader addresses are the same on every run; needs descriptor wallets
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Mine to an address the Miner already has under the "Mining Reward" label,
+    /// if any, instead of generating a new one on every run
+    #[arg(long)]
+    pub reuse_address: bool,
+
+    /// Type of the mining and Trader addresses the wallets generate
+    /// (bech32m for taproot) [default: the wallet's own default]
+    #[arg(long, value_name = "TYPE", value_parser = address_type_parser())]
+    pub address_type: Option<AddressType>,
+
+    /// Receive into a watch-only Trader wallet ("TraderWatchOnly") built from
+    /// this extended public key (tpub on regtest) via
+    /// wpkh(XPUB/0/*) and wpkh(XPUB/1/*) descriptors
+    #[arg(long, value_name = "XPUB")]
+    pub trader_xpub: Option<Xpub>,
+
+    /// Name of the Miner wallet (also its /wallet/<name> RPC path)
+    #[arg(long, value_name = "NAME", default_value = "Miner")]
+    pub miner_wallet: String,
+
+    /// Name of the Trader wallet [default: Trader, or TraderWatchOnly with
+    /// --trader-xpub]
+    #[arg(long, value_name = "NAME")]
+    pub trader_wallet: Option<String>,
+
+    /// Spread the mining rewards over N "Mining Reward" addresses, one per batch
+    /// of blocks; the first is still the reported mining address
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub reward_addresses: u32,
+
+    /// Coinbase-producing blocks to mine before the send; more than the default
+    /// over-funds the Miner. Blocks needed to mature the first one come on top.
+    #[arg(long, value_name = "N", default_value_t = mining::DEFAULT_FUNDING_BLOCKS, value_parser = clap::value_parser!(u64).range(1..))]
+    pub funding_blocks: u64,
+
+    /// Pause this many milliseconds after each mining batch, before the balance
+    /// is checked again, to go easier on slow or remote nodes
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub poll_interval_ms: u64,
+
+    /// Unload the Miner and Trader wallets before exiting (also on error)
+    #[arg(long)]
+    pub unload_on_exit: bool,
+
+    /// Unit for displayed balances and the amounts in out.txt (out.json is always BTC)
+    #[arg(long, value_enum, default_value_t = Unit::Btc)]
+    pub unit: Unit,
+
+    /// Layout of the report written to --out: the positional out.txt lines,
+    /// JSON, or key=value lines (out.json is written alongside either way)
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+
+    /// After sending, replace the transaction via bumpfee at this fee rate (sat/vB)
+    /// before confirming it. The send always signals BIP 125 replaceability.
+    /// A sweep has no change output to take the extra fee from, so it cannot be
+    /// bumped.
+    #[arg(long, value_name = "SAT_PER_VB", value_parser = parse_fee_rate, conflicts_with_all = ["sweep", "sendall"])]
+    pub bump_fee_rate: Option<f64>,
+
+    /// Confirmation target in blocks for estimatesmartfee when --fee-rate is not given
+    #[arg(long, value_name = "N", default_value_t = 6, value_parser = clap::value_parser!(u16).range(1..))]
+    pub conf_target: u16,
+
+    /// Before sending, preview the estimatesmartfee rate for each of these
+    /// confirmation targets (e.g. 1,3,6,25), then ask which to use when stdin
+    /// is a terminal; otherwise --conf-target is used. Not with --fee-rate,
+    /// which makes the estimates moot
+    #[arg(
+        long,
+        value_name = "N,...",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u16).range(1..),
+        conflicts_with = "fee_rate"
+    )]
+    pub confirm_target_blocks: Vec<u16>,
+
+    /// Where to write the transaction report; missing parent directories are created
+    /// [default: ../out.txt]
+    #[arg(long, value_name = "PATH")]
+    pub out: Option<PathBuf>,
+
+    /// File each send appends a timestamp, txid, fee and final Miner balance to
+    /// [default: runs.log next to the report]
+    #[arg(long, value_name = "PATH")]
+    pub run_log: Option<PathBuf>,
+
+    /// If the report file already names a transaction the Miner wallet knows,
+    /// print its txid and exit without mining or sending again
+    #[arg(long)]
+    pub skip_if_exists: bool,
+
+    /// Print the sent transaction's raw hex and its decoderawtransaction
+    /// structure before writing the report
+    #[arg(long)]
+    pub show_raw: bool,
+
+    /// List the labels of both wallets with their addresses, then exit
+    /// without mining or sending
+    #[arg(long)]
+    pub list_labels: bool,
+
+    /// Only set up a funded chain: mine the --funding-blocks and their
+    /// maturity blocks, report the Miner balance and exit without sending
+    #[arg(long)]
+    pub generate_only: bool,
+
+    /// Only fund the Trader: mine, send this many BTC to the Trader, confirm
+    /// it and report the Trader balance, without the transaction report
+    #[arg(
+        long,
+        value_name = "BTC",
+        value_parser = parse_btc,
+        conflicts_with_all = [
+            "generate_only", "amount", "recipient", "split", "sweep", "sendall", "dry_run",
+            "no_confirm", "mine_blocks"
+        ]
+    )]
+    pub fund_trader: Option<Amount>,
+
+    /// Before mining, print the node's chain tips (getchaintips) and warn if a
+    /// valid fork competes with the active chain
+    #[arg(long)]
+    pub check_tips: bool,
+
+    /// Number of confirmations to wait for after sending (mines one block per poll)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub confirmations: u32,
+
+    /// Mine exactly N blocks after the send instead of polling for
+    /// confirmations; the report still names the block containing the transaction
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "confirmations")]
+    pub mine_blocks: Option<u64>,
+
+    /// Leave the sent transaction in the mempool instead of mining a block for
+    /// it; the report then has block height -1 and block hash "unconfirmed"
+    #[arg(long, conflicts_with_all = ["confirmations", "mine_blocks"])]
+    pub no_confirm: bool,
+}
+
+// Individual steps of the flow, for scripting. They share all the options above,
+// which go before the subcommand name (e.g. `--amount 5 send`).
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Only mine until the Miner wallet can afford the send
+    Mine,
+    /// Only send from an already funded Miner wallet, confirm and write the report
+    Send,
+    /// Regenerate the report files for an existing, confirmed Miner transaction
+    Report {
+        /// Transaction to report on
+        txid: Txid,
+    },
+}
+
+// An extra `--recipient` payment, not yet checked against `--network`.
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub address: Address<NetworkUnchecked>,
+    pub amount: Amount,
+}
+
+// Parse `<ADDRESS>:<AMOUNT_BTC>`.
+fn parse_recipient(s: &str) -> Result<Recipient, String> {
+    let (address, amount) = s
+        .rsplit_once(':')
+        .ok_or_else(|| "expected <ADDRESS>:<AMOUNT_BTC>".to_owned())?;
+    Ok(Recipient {
+        address: address.parse().map_err(|e| format!("{}", e))?,
+        amount: parse_btc(amount)?,
+    })
+}
+
+// Parse a positive BTC amount such as `20` or `0.5` into an `Amount`.
+fn parse_btc(s: &str) -> Result<Amount, String> {
+    let amount = Amount::from_str_in(s, Denomination::Bitcoin).map_err(|e| e.to_string())?;
+    if amount == Amount::ZERO {
+        return Err("amount must be greater than zero".to_owned());
+    }
+    Ok(amount)
+}
+
+// `--address-type` values, as Core spells them for `getnewaddress`.
+fn address_type_parser() -> impl TypedValueParser<Value = AddressType> {
+    clap::builder::PossibleValuesParser::new(["bech32", "bech32m", "p2sh-segwit", "legacy"]).map(
+        |s| match s.as_str() {
+            "bech32" => AddressType::Bech32,
+            "bech32m" => AddressType::Bech32m,
+            "p2sh-segwit" => AddressType::P2shSegwit,
+            _ => AddressType::Legacy,
+        },
+    )
+}
+
+// Largest OP_RETURN payload Core relays by default (`-datacarriersize`).
+const MAX_OP_RETURN_BYTES: usize = 80;
+
+// Parse an OP_RETURN payload given as hex, within the standard size limit.
+fn parse_op_return(s: &str) -> Result<String, String> {
+    let data = Vec::<u8>::from_hex(s).map_err(|e| format!("invalid hex: {}", e))?;
+    if data.len() > MAX_OP_RETURN_BYTES {
+        return Err(format!(
+            "OP_RETURN payload is {} bytes, above the standard limit of {} bytes",
+            data.len(),
+            MAX_OP_RETURN_BYTES
+        ));
+    }
+    Ok(data.to_lower_hex_string())
+}
+
+// Parse a strictly positive fee rate in sat/vB.
+fn parse_fee_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err("fee rate must be a positive number of sat/vB".to_owned());
+    }
+    Ok(rate)
+}
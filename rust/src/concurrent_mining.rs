@@ -0,0 +1,111 @@
+// Concurrent variant of `mining::mine_to_maturity`, built with
+// `--features concurrent-mining`. bitcoincore-rpc has no async client, so both
+// sides run their blocking RPC calls on tokio's blocking pool, each over its
+// own connection: one task mines while the other polls the balance and stops
+// the miner as soon as the target is spendable.
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash};
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::mining::{self, MiningProgress, MINING_BATCH};
+use crate::node::{Node, NodeApi};
+
+// How often the polling task checks the Miner balance.
+const BALANCE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Mine `funding_blocks` plus the blocks needed to mature the first of them in
+// batches, then single blocks until the balance seen by `poller` reaches
+// `target_spendable`. `miner` and `poller` must be separate connections to the
+// same wallet. Since the miner only checks the stop signal between blocks, it
+// may mine one block more than the synchronous loop would. Returns every block
+// mined. The miner pauses `poll_interval` after each batch; progress is shown
+// and logged as by the synchronous loop.
+pub fn mine_to_maturity(
+    miner: Node,
+    poller: Node,
+    addrs: Vec<Address>,
+    funding_blocks: u64,
+    target_spendable: Amount,
+    poll_interval: Duration,
+    progress: bool,
+) -> Result<Vec<BlockHash>> {
+    assert!(!addrs.is_empty(), "at least one reward address is needed");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .build()?;
+    let initial_blocks = funding_blocks + mining::maturity_blocks(funding_blocks);
+    let progress = Arc::new(MiningProgress::new(progress, initial_blocks));
+    let result = runtime.block_on(async {
+        let funded = Arc::new(AtomicBool::new(false));
+        // Blocks mined so far, for the poller's progress updates
+        let mined = Arc::new(AtomicUsize::new(0));
+
+        let stop = Arc::clone(&funded);
+        let mined_so_far = Arc::clone(&mined);
+        let miner_progress = Arc::clone(&progress);
+        let mut mining = tokio::task::spawn_blocking(move || -> Result<Vec<BlockHash>> {
+            let mut reward_addrs = addrs.iter().cycle();
+            let mut hashes = Vec::new();
+            while (hashes.len() as u64) < initial_blocks || !stop.load(Ordering::Acquire) {
+                mining::check_stop(hashes.len())?;
+                let missing = initial_blocks.saturating_sub(hashes.len() as u64);
+                if missing == 0 {
+                    miner_progress.extend(1);
+                }
+                let batch = MINING_BATCH.min(missing).max(1);
+                let addr = reward_addrs.next().expect("cycle over a non-empty slice");
+                hashes.extend(miner.mine(batch, addr)?);
+                mined_so_far.store(hashes.len(), Ordering::Release);
+                std::thread::sleep(poll_interval);
+            }
+            Ok(hashes)
+        });
+
+        let poller = Arc::new(poller);
+        let mut interval = tokio::time::interval(BALANCE_POLL_INTERVAL);
+        let mut reported = 0;
+        loop {
+            tokio::select! {
+                // The miner only finishes on its own when it failed
+                result = &mut mining => return result.expect("mining task panicked"),
+                _ = interval.tick() => {}
+            }
+            let rpc = Arc::clone(&poller);
+            let balance = tokio::task::spawn_blocking(move || rpc.balance())
+                .await
+                .expect("balance task panicked");
+            // Only report once more blocks were mined, not on every poll
+            if let Ok(balance) = balance {
+                let mined = mined.load(Ordering::Acquire);
+                if mined != reported {
+                    progress.update(mined, balance);
+                    reported = mined;
+                }
+            }
+            match balance {
+                Ok(balance) if balance >= target_spendable => {
+                    info!("Spendable balance {} reached; stopping the miner", balance);
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // Let the miner wind down before reporting the failure
+                    funded.store(true, Ordering::Release);
+                    let _ = mining.await;
+                    return Err(e);
+                }
+            }
+        }
+        funded.store(true, Ordering::Release);
+        mining.await.expect("mining task panicked")
+    });
+    progress.finish();
+
+    let hashes = result?;
+    let top_up = (hashes.len() as u64).saturating_sub(initial_blocks);
+    mining::log_mining_summary(funding_blocks, top_up, target_spendable);
+    Ok(hashes)
+}
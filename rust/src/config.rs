@@ -0,0 +1,63 @@
+use bitcoincore_rpc::bitcoin::{Amount, Network};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::Cli;
+use crate::error::Result;
+
+// Settings that can be kept in a `--config` TOML file between runs. Any key left
+// out of the file keeps its built-in default, which matches the regtest setup
+// from docker-compose.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rpc_url: String,
+    pub rpc_user: String,
+    pub rpc_pass: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub amount: Amount,
+    pub network: Network,
+    pub out: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            rpc_url: crate::RPC_URL.to_owned(),
+            rpc_user: crate::RPC_USER.to_owned(),
+            rpc_pass: crate::RPC_PASS.to_owned(),
+            amount: Amount::from_int_btc(20),
+            network: Network::Regtest,
+            out: PathBuf::from("../out.txt"),
+        }
+    }
+}
+
+impl Config {
+    // Apply explicit command-line flags on top of the file values. The RPC
+    // settings are merged separately, because the `BITCOIN_RPC_*` environment
+    // variables sit between the flags and the file.
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(amount) = cli.amount {
+            self.amount = amount;
+        }
+        if let Some(network) = cli.network {
+            self.network = network;
+        }
+        if let Some(out) = &cli.out {
+            self.out = out.clone();
+        }
+    }
+}
+
+// Read a TOML config file such as:
+//
+//     rpc_url = "http://127.0.0.1:18443"
+//     amount = 20
+//     network = "regtest"
+//     out = "../out.txt"
+pub fn load_config(path: &Path) -> Result<Config> {
+    let text = fs::read_to_string(path)?;
+    Ok(toml::from_str(&text)?)
+}
@@ -0,0 +1,209 @@
+use bitcoincore_rpc::bitcoin::amount::ParseAmountError;
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::jsonrpc::{self, simple_http};
+use std::fmt;
+use std::io;
+
+// Application-level error, categorised by what actually went wrong so callers
+// can match on the real cause instead of a catch-all RPC error.
+#[derive(Debug)]
+pub enum AppError {
+    // An address returned by the node is not valid for the expected network.
+    AddressValidation(String),
+    // The node rejected or failed an RPC call.
+    Rpc(bitcoincore_rpc::Error),
+    // An RPC call got no response within `--rpc-timeout`.
+    Timeout(bitcoincore_rpc::Error),
+    // Reading or writing a local file failed.
+    Io(io::Error),
+    // (De)serializing JSON failed.
+    Json(serde_json::Error),
+    // The `--config` file is not valid TOML or has unexpected keys.
+    Config(toml::de::Error),
+    // A BTC amount could not be parsed or converted.
+    InvalidAmount(ParseAmountError),
+    // A command-line option has a value we cannot act on.
+    InvalidArgument(String),
+    // The chain or wallet is not in the state the flow expects.
+    InvalidState(String),
+    // The send pays more fee than `--max-fee` allows.
+    FeeAboveCap {
+        fee: Amount,
+        max_fee: Amount,
+    },
+    // The sending wallet cannot cover the requested amount (including the fee
+    // budget, where one applies).
+    InsufficientFunds {
+        available: Amount,
+        requested: Amount,
+    },
+    // Ctrl-C stopped the mining loop after `mined_blocks` blocks.
+    Interrupted {
+        mined_blocks: usize,
+    },
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::AddressValidation(msg) => write!(f, "address validation error: {}", msg),
+            AppError::Rpc(e) => write!(f, "RPC error: {}", e),
+            AppError::Timeout(e) => write!(f, "RPC call timed out: {}", e),
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+            AppError::Json(e) => write!(f, "JSON error: {}", e),
+            AppError::Config(e) => write!(f, "config file error: {}", e),
+            AppError::InvalidAmount(e) => write!(f, "invalid amount: {}", e),
+            AppError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            AppError::InvalidState(msg) => write!(f, "invalid state: {}", msg),
+            AppError::FeeAboveCap { fee, max_fee } => {
+                write!(f, "fee of {} exceeds the --max-fee cap of {}", fee, max_fee)
+            }
+            AppError::InsufficientFunds {
+                available,
+                requested,
+            } => write!(
+                f,
+                "insufficient funds: {} available, {} required (short by {})",
+                available,
+                requested,
+                requested.checked_sub(*available).unwrap_or(Amount::ZERO)
+            ),
+            AppError::Interrupted { mined_blocks } => {
+                write!(f, "interrupted after mining {} block(s)", mined_blocks)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Rpc(e) | AppError::Timeout(e) => Some(e),
+            AppError::Io(e) => Some(e),
+            AppError::Json(e) => Some(e),
+            AppError::Config(e) => Some(e),
+            AppError::InvalidAmount(e) => Some(e),
+            AppError::AddressValidation(_)
+            | AppError::InvalidArgument(_)
+            | AppError::InvalidState(_)
+            | AppError::FeeAboveCap { .. }
+            | AppError::InsufficientFunds { .. }
+            | AppError::Interrupted { .. } => None,
+        }
+    }
+}
+
+// Process exit codes for the error categories scripts may want to tell apart;
+// every other error exits with 1.
+pub const EXIT_CONNECTION: i32 = 2;
+pub const EXIT_INSUFFICIENT_FUNDS: i32 = 3;
+pub const EXIT_ADDRESS_VALIDATION: i32 = 4;
+pub const EXIT_WRITE: i32 = 5;
+// As for a process killed by SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+impl AppError {
+    // The process exit code `main` ends with for this error. The node being
+    // unreachable (refused, reset or timed out) counts as a connection failure,
+    // an error it answered with does not.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Timeout(_)
+            | AppError::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_))) => {
+                EXIT_CONNECTION
+            }
+            AppError::InsufficientFunds { .. } => EXIT_INSUFFICIENT_FUNDS,
+            AppError::AddressValidation(_) => EXIT_ADDRESS_VALIDATION,
+            AppError::Io(_) => EXIT_WRITE,
+            AppError::Interrupted { .. } => EXIT_INTERRUPTED,
+            _ => 1,
+        }
+    }
+}
+
+// Whether `e` is the HTTP transport giving up on a response. A socket read
+// timeout surfaces as `TimedOut` or, on Unix, `WouldBlock`.
+fn is_timeout(e: &bitcoincore_rpc::Error) -> bool {
+    match e {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(transport)) => matches!(
+            transport.downcast_ref::<simple_http::Error>(),
+            Some(simple_http::Error::SocketError(e))
+                if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+        ),
+        _ => false,
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for AppError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        if is_timeout(&e) {
+            AppError::Timeout(e)
+        } else {
+            AppError::Rpc(e)
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Json(e)
+    }
+}
+
+impl From<toml::de::Error> for AppError {
+    fn from(e: toml::de::Error) -> Self {
+        AppError::Config(e)
+    }
+}
+
+impl From<ParseAmountError> for AppError {
+    fn from(e: ParseAmountError) -> Self {
+        AppError::InvalidAmount(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport_error(kind: io::ErrorKind) -> bitcoincore_rpc::Error {
+        let socket = simple_http::Error::SocketError(io::Error::new(kind, "socket"));
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(Box::new(socket)))
+    }
+
+    #[test]
+    fn exit_codes_follow_the_error_category() {
+        let refused = AppError::from(transport_error(io::ErrorKind::ConnectionRefused));
+        assert!(matches!(refused, AppError::Rpc(_)));
+        assert_eq!(refused.exit_code(), EXIT_CONNECTION);
+        let timed_out = AppError::from(transport_error(io::ErrorKind::TimedOut));
+        assert!(matches!(timed_out, AppError::Timeout(_)));
+        assert_eq!(timed_out.exit_code(), EXIT_CONNECTION);
+
+        let short = AppError::InsufficientFunds {
+            available: Amount::from_sat(1),
+            requested: Amount::from_sat(2),
+        };
+        assert_eq!(short.exit_code(), EXIT_INSUFFICIENT_FUNDS);
+        assert_eq!(
+            AppError::AddressValidation("wrong network".to_owned()).exit_code(),
+            EXIT_ADDRESS_VALIDATION
+        );
+        assert_eq!(
+            AppError::from(io::Error::new(io::ErrorKind::PermissionDenied, "out.txt")).exit_code(),
+            EXIT_WRITE
+        );
+        // Anything else, including an error the node answered with
+        assert_eq!(AppError::InvalidState("no".to_owned()).exit_code(), 1);
+        let rejected = bitcoincore_rpc::Error::ReturnedError("rejected".to_owned());
+        assert_eq!(AppError::from(rejected).exit_code(), 1);
+    }
+}
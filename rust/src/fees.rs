@@ -0,0 +1,107 @@
+use bitcoincore_rpc::bitcoin::Amount;
+use log::{info, warn};
+
+use crate::error::Result;
+use crate::node::Node;
+
+// Fee rate used when the node has no estimate yet, which is the norm on a fresh
+// regtest chain. Matches the `fallbackfee=0.00001` (BTC/kvB) in bitcoin.conf.
+pub const FALLBACK_FEE_RATE_SAT_VB: f64 = 1.0;
+
+// Rough virtual sizes used to budget the fee before the transaction exists:
+// fixed overhead, a P2WPKH input, and an output (sized for P2TR, the largest
+// standard one we pay to).
+const TX_OVERHEAD_VBYTES: u64 = 11;
+const INPUT_VBYTES: u64 = 68;
+const OUTPUT_VBYTES: u64 = 43;
+// Inputs assumed when budgeting; one mature coinbase usually covers the send,
+// the second leaves headroom for coin selection picking more.
+const BUDGETED_INPUTS: u64 = 2;
+
+// Upper-bound fee for a send paying `payments` outputs (plus change) at
+// `fee_rate` sat/vB, used to check the balance before asking Core to fund it.
+pub fn fee_buffer(fee_rate: f64, payments: usize) -> Amount {
+    let vsize =
+        TX_OVERHEAD_VBYTES + BUDGETED_INPUTS * INPUT_VBYTES + (payments as u64 + 1) * OUTPUT_VBYTES;
+    Amount::from_sat((fee_rate * vsize as f64).ceil() as u64)
+}
+
+// Ask `estimatesmartfee` for a fee rate that should confirm within `conf_target`
+// blocks, converted from BTC/kvB to sat/vB. Returns `None` when the node has no
+// estimate.
+pub fn estimate_fee_rate(rpc: &Node, conf_target: u16) -> Result<Option<f64>> {
+    let estimate = rpc.estimate_smart_fee(conf_target)?;
+    Ok(estimate
+        .fee_rate
+        .map(|per_kvb| per_kvb.to_sat() as f64 / 1000.0))
+}
+
+// Pick the fee rate for the send: an explicit `--fee-rate` wins, otherwise the
+// node's estimate for `conf_target`, otherwise the fallback rate.
+pub fn choose_fee_rate(rpc: &Node, explicit: Option<f64>, conf_target: u16) -> Result<f64> {
+    if let Some(rate) = explicit {
+        info!("Using explicit fee rate of {} sat/vB", rate);
+        return Ok(rate);
+    }
+    match estimate_fee_rate(rpc, conf_target)? {
+        Some(rate) => {
+            info!(
+                "estimatesmartfee suggests {} sat/vB for confirmation within {} blocks",
+                rate, conf_target
+            );
+            Ok(rate)
+        }
+        None => {
+            warn!(
+                "No fee estimate available for {} blocks; falling back to {} sat/vB",
+                conf_target, FALLBACK_FEE_RATE_SAT_VB
+            );
+            Ok(FALLBACK_FEE_RATE_SAT_VB)
+        }
+    }
+}
+
+// The estimate for one confirmation target in a fee preview.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeePreview {
+    pub conf_target: u16,
+    // sat/vB, or `None` when the node has no estimate for this target.
+    pub fee_rate: Option<f64>,
+}
+
+// `estimatesmartfee` for each of `targets`, in order.
+pub fn preview_fee_rates(rpc: &Node, targets: &[u16]) -> Result<Vec<FeePreview>> {
+    targets
+        .iter()
+        .map(|&conf_target| {
+            Ok(FeePreview {
+                conf_target,
+                fee_rate: estimate_fee_rate(rpc, conf_target)?,
+            })
+        })
+        .collect()
+}
+
+// Print `previews` as a table of target, fee rate and the fee that rate means
+// for a send paying `payments` outputs (see `fee_buffer`). Targets without an
+// estimate show the fallback rate the send would use instead.
+pub fn print_fee_preview(previews: &[FeePreview], payments: usize) {
+    say!("{:>8} {:>12} {:>12}", "Target", "sat/vB", "Fee (sat)");
+    for preview in previews {
+        match preview.fee_rate {
+            Some(rate) => say!(
+                "{:>8} {:>12.3} {:>12}",
+                preview.conf_target,
+                rate,
+                fee_buffer(rate, payments).to_sat()
+            ),
+            None => say!(
+                "{:>8} {:>12} {:>12}  no estimate; falls back to {} sat/vB",
+                preview.conf_target,
+                "-",
+                fee_buffer(FALLBACK_FEE_RATE_SAT_VB, payments).to_sat(),
+                FALLBACK_FEE_RATE_SAT_VB
+            ),
+        }
+    }
+}
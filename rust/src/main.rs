@@ -1,130 +1,543 @@
 #![allow(unused)]
 #![allow(clippy::uninlined_format_args)]
-use bitcoin::hex::DisplayHex;
-use bitcoincore_rpc::bitcoin::{Amount, Network};
-use bitcoincore_rpc::{Auth, Client, RpcApi};
-use serde::Deserialize;
-use serde_json::json;
+
+// Print human-readable output: to stdout normally, or as an info log line
+// (stderr) with --quiet, so stdout carries nothing but the txid.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::report::is_quiet() {
+            log::info!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod cli;
+#[cfg(feature = "concurrent-mining")]
+mod concurrent_mining;
+mod config;
+mod error;
+mod fees;
+mod mining;
+mod node;
+mod report;
+mod retry;
+mod tx;
+mod wallet;
+
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Network, Txid};
+use bitcoincore_rpc::json::{AddressType, GetBlockchainInfoResult};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::simple_http::SimpleHttpTransport;
+use bitcoincore_rpc::{Auth, Client};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
+use error::AppError;
+use log::{debug, error, info, warn};
+use node::{Node, NodeApi, SendOutcome};
+use report::TxReport;
+use std::env;
 use std::fs::File;
-use std::io::Write;
+use std::thread;
+use std::time::Duration;
+use tx::{Payment, SendOptions};
 
-// Node access params
+// Node access params (defaults for the corresponding CLI flags)
 const RPC_URL: &str = "http://127.0.0.1:18443"; // Default regtest RPC port
 const RPC_USER: &str = "alice";
 const RPC_PASS: &str = "password";
 
-// You can use calls not provided in RPC lib API using the generic `call` function.
-fn send(rpc: &Client, addr: &str) -> bitcoincore_rpc::Result<String> {
-    let args = [
-        json!([{addr : 100 }]), // recipient address
-        json!(null),            // conf target
-        json!(null),            // estimate mode
-        json!(null),            // fee rate in sats/vb
-        json!(null),            // Empty option object
-    ];
-
-    #[derive(Deserialize)]
-    struct SendResult {
-        complete: bool,
-        txid: String,
-    }
-    let send_result = rpc.call::<SendResult>("send", &args)?;
-    assert!(send_result.complete);
-    Ok(send_result.txid)
+// Wallet that stands in for the Trader with --trader-xpub, kept apart from a
+// spendable "Trader" wallet left by earlier runs.
+const TRADER_WATCH_ONLY_WALLET: &str = "TraderWatchOnly";
+const DEFAULT_TRADER_WALLET: &str = "Trader";
+
+// Split `total` into `parts` equal payments, each to a fresh Trader address in
+// its own transaction. Stops early, keeping what was sent so far, once the
+// Miner runs out of funds. Returns the txids of the successful sends.
+fn send_split(
+    miner_rpc: &Node,
+    trader_rpc: &Node,
+    total: Amount,
+    parts: u32,
+    network: Network,
+    address_type: Option<AddressType>,
+    options: &SendOptions,
+) -> Result<Vec<Txid>, AppError> {
+    let part = total / parts as u64;
+    let mut txids = Vec::new();
+    for i in 1..=parts {
+        let address = tx::checked_address(trader_rpc.new_address("Split", address_type)?, network)?;
+        match miner_rpc
+            .send(&address, part, options)
+            .and_then(|outcome| finish_send(miner_rpc, outcome))
+        {
+            Ok(txid) => {
+                info!(
+                    "Split payment {}/{}: {} BTC in {}",
+                    i,
+                    parts,
+                    part.to_btc(),
+                    txid
+                );
+                txids.push(txid);
+            }
+            Err(AppError::Rpc(e)) if wallet::is_insufficient_funds_error(&e) => {
+                warn!(
+                    "Out of funds after {} of {} split payments: {}",
+                    txids.len(),
+                    parts,
+                    e
+                );
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(txids)
 }
 
-fn main() -> bitcoincore_rpc::Result<()> {
-    // Connect to Bitcoin Core RPC
-    let rpc = Client::new(
-        RPC_URL,
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+// Oldest Bitcoin Core supported: `send` and sat/vB `fee_rate` options arrived
+// in v0.21.0 (`getnetworkinfo` reports versions as MMmmpp, e.g. 240001).
+const MIN_NODE_VERSION: usize = 210000;
+// The `sendall` RPC behind --sendall arrived in v24.0.0.
+const SENDALL_MIN_VERSION: usize = 240000;
 
-    // Get blockchain info
-    let blockchain_info = rpc.get_blockchain_info()?;
-    println!("Blockchain Info: {:?}", blockchain_info);
+// Startup retry policy while the node may still be booting
+const CONNECT_ATTEMPTS: u32 = 5;
+const CONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
 
-    // Create/Load the wallets, named 'Miner' and 'Trader'
-    // For Miner wallet
-    match rpc.create_wallet("Miner", None, None, None, None) {
-        Ok(_) => {
-            println!("Created Miner wallet");
+// How long --wallet-passphrase unlocks the Miner wallet; it is locked again
+// right after the send anyway
+const UNLOCK_TIMEOUT_SECS: u64 = 60;
+
+// Send retry policy for --keep-going
+const SEND_ATTEMPTS: u32 = 3;
+const SEND_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// The txid of a send, signing and broadcasting it here when the wallet left it
+// as an incomplete PSBT (see tx::sign_and_broadcast_psbt).
+fn finish_send(rpc: &Node, outcome: SendOutcome) -> Result<Txid, AppError> {
+    match outcome {
+        SendOutcome::Broadcast(txid) => Ok(txid),
+        SendOutcome::NeedsSigning(psbt) => {
+            warn!("send left the transaction unsigned; signing the returned PSBT");
+            tx::sign_and_broadcast_psbt(rpc, &psbt)
         }
-        Err(_) => {
-            println!("Miner wallet already exists, attempting to load...");
-            match rpc.load_wallet("Miner") {
-                Ok(_) => println!("Loaded Miner wallet"),
-                Err(e) => println!("Miner wallet load result: {:?}", e),
+    }
+}
+
+// Connection parameters for the node, resolved once at startup.
+struct RpcConfig {
+    url: String,
+    auth: Auth,
+    // Per-request timeout, so a hung node cannot block a call forever.
+    timeout: Duration,
+}
+
+// Resolve one connection setting. Precedence is: explicit CLI flag, then the
+// environment variable, then the config file (or built-in regtest default).
+fn resolve_setting(flag: &Option<String>, env_key: &str, default: &str) -> String {
+    match flag {
+        Some(value) => value.clone(),
+        None => env::var(env_key).unwrap_or_else(|_| default.to_owned()),
+    }
+}
+
+impl RpcConfig {
+    fn new(cli: &Cli, settings: &Config) -> Result<Self, AppError> {
+        // clap rejects `--cookie-file` combined with `--rpc-user`/`--rpc-pass`;
+        // credentials from the environment are just as ambiguous
+        let auth = match &cli.cookie_file {
+            Some(_) if env::var_os("BITCOIN_RPC_USER").is_some() => {
+                return Err(AppError::InvalidArgument(
+                    "--cookie-file cannot be combined with BITCOIN_RPC_USER".to_owned(),
+                ))
             }
-        }
+            Some(_) if env::var_os("BITCOIN_RPC_PASS").is_some() => {
+                return Err(AppError::InvalidArgument(
+                    "--cookie-file cannot be combined with BITCOIN_RPC_PASS".to_owned(),
+                ))
+            }
+            Some(path) => Auth::CookieFile(path.clone()),
+            None => Auth::UserPass(
+                resolve_setting(&cli.rpc_user, "BITCOIN_RPC_USER", &settings.rpc_user),
+                resolve_setting(&cli.rpc_pass, "BITCOIN_RPC_PASS", &settings.rpc_pass),
+            ),
+        };
+        Ok(RpcConfig {
+            url: resolve_setting(&cli.rpc_url, "BITCOIN_RPC_URL", &settings.rpc_url),
+            auth,
+            timeout: Duration::from_secs(cli.rpc_timeout),
+        })
+    }
+}
+
+// Build an RPC client for the base node (`None`) or for a specific wallet context.
+// All client construction goes through here so auth only needs changing in one place.
+fn connect_wallet(config: &RpcConfig, wallet_name: Option<&str>) -> Result<Node, AppError> {
+    let url = match wallet_name {
+        Some(name) => format!("{}/wallet/{}", config.url, wallet::url_encode(name)),
+        None => config.url.clone(),
     };
+    // `Client::new` would use the transport's fixed default timeout, so build
+    // the HTTP transport ourselves
+    let (user, pass) = config.auth.clone().get_user_pass()?;
+    let mut builder = SimpleHttpTransport::builder()
+        .url(&url)
+        .map_err(|e| bitcoincore_rpc::Error::JsonRpc(e.into()))?
+        .timeout(config.timeout);
+    if let Some(user) = user {
+        builder = builder.auth(user, pass);
+    }
+    Ok(Node::new(Client::from_jsonrpc(
+        jsonrpc::Client::with_transport(builder.build()),
+    )))
+}
 
-    // For Trader wallet
-    match rpc.create_wallet("Trader", None, None, None, None) {
-        Ok(_) => {
-            println!("Created Trader wallet");
+// Connect to the node and ping it with `getblockchaininfo`, retrying with
+// exponential backoff (delay, 2*delay, 4*delay, ...) while it is unreachable or
+// still warming up. Returns the connection with the `getblockchaininfo` result,
+// or the last error once all attempts are used.
+fn connect_with_retry(
+    config: &RpcConfig,
+    attempts: u32,
+    delay: Duration,
+) -> Result<(Node, GetBlockchainInfoResult), AppError> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        let result = connect_wallet(config, None)
+            .and_then(|node| node.blockchain_info().map(|info| (node, info)));
+        match result {
+            Ok(connected) => return Ok(connected),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "Node not reachable (attempt {}/{}): {}; retrying in {:?}",
+                    attempt, attempts, e, delay
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
         }
-        Err(_) => {
-            println!("Trader wallet already exists, attempting to load...");
-            match rpc.load_wallet("Trader") {
-                Ok(_) => println!("Loaded Trader wallet"),
-                Err(e) => println!("Trader wallet load result: {:?}", e),
+    }
+}
+
+// Refuse to run against a node on another chain than `--network`, or one too
+// old for the RPCs we use, before anything is created or mined. `blockchain_info`
+// is the node's `getblockchaininfo` result.
+fn check_node(
+    rpc: &Node,
+    blockchain_info: &GetBlockchainInfoResult,
+    network: Network,
+) -> Result<(), AppError> {
+    let chain = blockchain_info.chain;
+    if chain != network {
+        return Err(AppError::InvalidState(format!(
+            "node is running on {}, but --network is {}",
+            chain, network
+        )));
+    }
+    let network_info = rpc.network_info()?;
+    if network_info.version < MIN_NODE_VERSION {
+        return Err(AppError::InvalidState(format!(
+            "node version {} ({}) is too old; Bitcoin Core v0.21.0 ({}) or newer is required",
+            network_info.version, network_info.subversion, MIN_NODE_VERSION
+        )));
+    }
+    info!(
+        "Connected to {} on {}",
+        network_info.subversion.trim_matches('/'),
+        chain
+    );
+    Ok(())
+}
+
+// Whether the node has the `sendall` RPC; warns that --sendall falls back to
+// the --sweep send path when it does not.
+fn sendall_supported(rpc: &Node) -> Result<bool, AppError> {
+    let version = rpc.version()?;
+    if version < SENDALL_MIN_VERSION {
+        warn!(
+            "Node version {} has no sendall (needs {}); sweeping with send instead",
+            version, SENDALL_MIN_VERSION
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// Everything the steps of a run share: the parsed options, the resolved
+// settings and clients for the node and both wallets.
+struct App {
+    cli: Cli,
+    settings: Config,
+    // Kept to open further connections for concurrent mining.
+    #[cfg(feature = "concurrent-mining")]
+    rpc_config: RpcConfig,
+    rpc: Node,
+    miner_rpc: Node,
+    trader_rpc: Node,
+    // Wallet names: --miner-wallet, and --trader-wallet (by default "Trader",
+    // or TRADER_WATCH_ONLY_WALLET with --trader-xpub).
+    miner_wallet: String,
+    trader_wallet: String,
+    // Public receive descriptors when --deterministic (or --trader-xpub) is set.
+    miner_descriptor: Option<String>,
+    trader_descriptor: Option<String>,
+}
+
+impl App {
+    // Address the Miner mines to, labelled "Mining Reward". With --reuse-address
+    // an existing address under that label is used instead of a fresh one.
+    fn mining_address(&self) -> Result<Address, AppError> {
+        let existing = if self.cli.reuse_address {
+            wallet::address_by_label(&self.miner_rpc, "Mining Reward")?
+        } else {
+            None
+        };
+        let unchecked = match (existing, &self.miner_descriptor) {
+            (Some(address), _) => {
+                info!("Reusing existing \"Mining Reward\" address");
+                address
             }
+            (None, Some(desc)) => {
+                wallet::derive_address(&self.miner_rpc, desc, 0, "Mining Reward")?
+            }
+            (None, None) => self
+                .miner_rpc
+                .new_address("Mining Reward", self.cli.address_type)?,
+        };
+        // Validate the address for the configured network
+        let address = tx::checked_address(unchecked, self.settings.network)?;
+        info!("Mining address: {}", address);
+        Ok(address)
+    }
+
+    // Addresses to rotate the mining rewards over: `primary` (the mining
+    // address the report and later steps use) followed by --reward-addresses - 1
+    // further "Mining Reward" addresses
+    fn reward_addresses(&self, primary: &Address) -> Result<Vec<Address>, AppError> {
+        let mut addresses = vec![primary.clone()];
+        for index in 1..self.cli.reward_addresses {
+            let unchecked = match &self.miner_descriptor {
+                Some(desc) => {
+                    wallet::derive_address(&self.miner_rpc, desc, index, "Mining Reward")?
+                }
+                None => self
+                    .miner_rpc
+                    .new_address("Mining Reward", self.cli.address_type)?,
+            };
+            addresses.push(tx::checked_address(unchecked, self.settings.network)?);
+        }
+        if addresses.len() > 1 {
+            info!("Rotating mining rewards over {} addresses", addresses.len());
         }
+        Ok(addresses)
+    }
+
+    // Total the Miner pays out: --amount (20 BTC by default) plus any extra
+    // --recipient payments
+    fn total_send(&self) -> Amount {
+        self.settings.amount + self.cli.recipient.iter().map(|r| r.amount).sum()
+    }
+}
+
+// Connect to the node, check it, and create or load both wallets.
+fn setup(cli: Cli) -> Result<App, AppError> {
+    // Resolve settings (CLI flag > BITCOIN_RPC_* environment variable >
+    // --config file > regtest default)
+    let mut settings = match &cli.config {
+        Some(path) => config::load_config(path)?,
+        None => Config::default(),
     };
+    settings.apply_cli(&cli);
+    let config = RpcConfig::new(&cli, &settings)?;
 
-    // Connect to specific wallet contexts
-    let miner_rpc = Client::new(
-        &format!("{}/wallet/Miner", RPC_URL),
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+    // Connect to Bitcoin Core RPC, waiting for the node if it is still starting
+    let (rpc, blockchain_info) =
+        connect_with_retry(&config, CONNECT_ATTEMPTS, CONNECT_INITIAL_DELAY)?;
+    debug!("Blockchain Info: {:?}", blockchain_info);
 
-    let trader_rpc = Client::new(
-        &format!("{}/wallet/Trader", RPC_URL),
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+    // Make sure this is the node we expect before touching any wallet
+    check_node(&rpc, &blockchain_info, settings.network)?;
 
-    // Generate one address from the Miner wallet with label "Mining Reward"
-    let mining_address_unchecked = miner_rpc.get_new_address(Some("Mining Reward"), None)?;
-    // Validate the address for regtest network
-    let mining_address = mining_address_unchecked
-        .require_network(Network::Regtest)
-        .map_err(|e| {
-            bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(
-                format!("Address validation error: {}", e).into(),
-            ))
-        })?;
-    println!("Mining address: {}", mining_address);
+    // Create/Load the wallets, named 'Miner' and 'Trader' unless --miner-wallet
+    // and --trader-wallet say otherwise. With --trader-xpub the Trader side is a
+    // watch-only descriptor wallet, by default a separate TRADER_WATCH_ONLY_WALLET.
+    // With --wallet-dir, each name becomes the wallet's path in that directory.
+    let wallet_id = |name: String| match &cli.wallet_dir {
+        Some(dir) => wallet::wallet_path(dir, &name),
+        None => Ok(name),
+    };
+    let miner_wallet = wallet_id(cli.miner_wallet.clone())?;
+    wallet::create_or_load_wallet(&rpc, &miner_wallet, cli.descriptor)?;
+    let (trader_wallet, trader_descriptors) = if cli.trader_xpub.is_some() {
+        let name = cli
+            .trader_wallet
+            .clone()
+            .unwrap_or_else(|| TRADER_WATCH_ONLY_WALLET.to_owned());
+        let name = wallet_id(name)?;
+        wallet::create_or_load_watch_only_wallet(&rpc, &name)?;
+        (name, Some(true))
+    } else {
+        let name = cli
+            .trader_wallet
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TRADER_WALLET.to_owned());
+        let name = wallet_id(name)?;
+        wallet::create_or_load_wallet(&rpc, &name, cli.descriptor)?;
+        (name, cli.descriptor)
+    };
+
+    // Connect to specific wallet contexts (/wallet/<name>)
+    let miner_rpc = connect_wallet(&config, Some(&miner_wallet))?;
+    let trader_rpc = connect_wallet(&config, Some(&trader_wallet))?;
+    wallet::check_wallet_type(&miner_rpc, &miner_wallet, cli.descriptor)?;
+    wallet::check_wallet_type(&trader_rpc, &trader_wallet, trader_descriptors)?;
+
+    // Optionally pick up history the wallets had before they were loaded here
+    if cli.rescan {
+        wallet::rescan_wallet(&miner_rpc, &miner_wallet, cli.rescan_from)?;
+        wallet::rescan_wallet(&trader_rpc, &trader_wallet, cli.rescan_from)?;
+    }
+
+    // With --deterministic, both wallets use fixed descriptors so the mining and
+    // Trader addresses are the same on every run. A --trader-xpub takes the
+    // Trader's place, and the Trader then receives on its first address.
+    let miner_descriptor = if cli.deterministic {
+        Some(wallet::import_deterministic_descriptors(
+            &miner_rpc,
+            &miner_wallet,
+            &wallet::MINER_SEED,
+            settings.network,
+        )?)
+    } else {
+        None
+    };
+    let trader_descriptor = match &cli.trader_xpub {
+        Some(xpub) => Some(wallet::import_watch_only_xpub(
+            &trader_rpc,
+            &trader_wallet,
+            xpub,
+        )?),
+        None if cli.deterministic => Some(wallet::import_deterministic_descriptors(
+            &trader_rpc,
+            &trader_wallet,
+            &wallet::TRADER_SEED,
+            settings.network,
+        )?),
+        None => None,
+    };
+
+    // Descriptor-derived addresses are always wpkh, whatever --address-type says
+    match cli.address_type {
+        Some(kind) if miner_descriptor.is_some() || trader_descriptor.is_some() => warn!(
+            "--address-type {:?} does not apply to addresses derived from imported \
+             descriptors, which are bech32",
+            kind
+        ),
+        Some(kind) => info!("Generating {:?} addresses", kind),
+        None => info!("Generating addresses of the wallets' default type"),
+    }
+
+    Ok(App {
+        cli,
+        settings,
+        #[cfg(feature = "concurrent-mining")]
+        rpc_config: config,
+        rpc,
+        miner_rpc,
+        trader_rpc,
+        miner_wallet,
+        trader_wallet,
+        miner_descriptor,
+        trader_descriptor,
+    })
+}
+
+// Spendable balance to keep mining for: enough for the send (the
+// --fund-trader amount plus at most --max-fee with --fund-trader), or just the
+// matured --funding-blocks with --generate-only since nothing is sent.
+fn mining_target(app: &App) -> Amount {
+    if app.cli.generate_only {
+        Amount::ZERO
+    } else if let Some(amount) = app.cli.fund_trader {
+        amount + app.cli.max_fee
+    } else {
+        app.total_send()
+    }
+}
+
+// `mine`: mine to `mining_address` until the Miner can afford the send.
+fn run_mine(app: &App, mining_address: &Address) -> Result<(), AppError> {
+    let (cli, miner_rpc) = (&app.cli, &app.miner_rpc);
 
     // Mine blocks until we get spendable balance
     // In Bitcoin, coinbase rewards need 100 confirmations to be spendable
     // So we need to mine at least 101 blocks to have spendable coins
-    let initial_balance = miner_rpc.get_balance(None, None)?;
-    println!("Initial Miner balance: {}", initial_balance);
-
-    let mut blocks_mined = 0;
-    loop {
-        // Mine 10 blocks at a time to the mining address
-        let _block_hashes = rpc.generate_to_address(10, &mining_address)?;
-        blocks_mined += 10;
+    let initial_balance = miner_rpc.balance()?;
+    info!("Initial Miner balance: {}", initial_balance);
 
-        let balance = miner_rpc.get_balance(None, None)?;
-        println!("After {} blocks, Miner balance: {}", blocks_mined, balance);
+    // Mining is only possible on regtest; bail out clearly anywhere else
+    mining::ensure_can_mine(app.settings.network)?;
+    if cli.check_tips {
+        mining::check_chain_tips(miner_rpc)?;
+    }
 
-        if balance > Amount::ZERO {
-            break;
-        }
+    // Mine the --funding-blocks, mature them, and top up if the send needs more
+    let reward_addresses = app.reward_addresses(mining_address)?;
+    let mining_phase = mining::MiningPhase::start();
+    #[cfg(not(feature = "concurrent-mining"))]
+    let mined_blocks = mining::mine_to_maturity(
+        miner_rpc,
+        &reward_addresses,
+        cli.funding_blocks,
+        mining_target(app),
+        Duration::from_millis(cli.poll_interval_ms),
+        cli.progress,
+    )?;
+    // The concurrent miner and balance poller each need their own connection
+    #[cfg(feature = "concurrent-mining")]
+    let mined_blocks = concurrent_mining::mine_to_maturity(
+        connect_wallet(&app.rpc_config, Some(&app.miner_wallet))?,
+        connect_wallet(&app.rpc_config, Some(&app.miner_wallet))?,
+        reward_addresses,
+        cli.funding_blocks,
+        mining_target(app),
+        Duration::from_millis(cli.poll_interval_ms),
+        cli.progress,
+    )?;
+    // A Ctrl-C that came after the last batch still stops the run
+    drop(mining_phase);
+    mining::check_stop(mined_blocks.len())?;
+    info!("Mined {} blocks in total", mined_blocks.len());
+    // Where the first reward actually matured, based on the real height of the
+    // first block we mined (the chain may not have started empty)
+    if let Some(first_block) = mined_blocks.first() {
+        let coinbase_height = miner_rpc.block_height(first_block)?;
+        info!(
+            "First coinbase reward (block {}) is spendable from chain height {}",
+            coinbase_height,
+            mining::first_spendable_height(coinbase_height)
+        );
+    }
 
-        if blocks_mined >= 110 {
-            break;
-        }
+    // Optionally back up the now funded Miner wallet
+    if let Some(dir) = &cli.backup_dir {
+        wallet::backup_wallet(miner_rpc, &app.miner_wallet, dir)?;
     }
 
     // Print the balance of the Miner wallet
-    let final_miner_balance = miner_rpc.get_balance(None, None)?;
-    println!(
-        "Final Miner wallet balance: {} BTC",
-        final_miner_balance.to_btc()
+    let final_miner_balance = miner_rpc.balance()?;
+    say!(
+        "Final Miner wallet balance: {} {}",
+        report::format_amount(final_miner_balance, cli.unit),
+        cli.unit
     );
 
     /*
@@ -135,134 +548,819 @@ fn main() -> bitcoincore_rpc::Result<()> {
     at least 101 blocks to see a positive spendable balance.
     */
 
-    // Create receiving address from Trader wallet with label "Received"
-    let trader_address_unchecked = trader_rpc.get_new_address(Some("Received"), None)?;
-    // Validate the address for regtest network
-    let trader_address = trader_address_unchecked
-        .require_network(Network::Regtest)
-        .map_err(|e| {
-            bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(
-                format!("Address validation error: {}", e).into(),
-            ))
+    Ok(())
+}
+
+// Create receiving address from Trader wallet with label "Received", checked
+// against the configured network.
+fn trader_receive_address(app: &App) -> Result<Address, AppError> {
+    let trader_address_unchecked = match &app.trader_descriptor {
+        Some(desc) => wallet::derive_address(&app.trader_rpc, desc, 0, "Received")?,
+        None => app
+            .trader_rpc
+            .new_address("Received", app.cli.address_type)?,
+    };
+    let trader_address = tx::checked_address(trader_address_unchecked, app.settings.network)?;
+    info!("Trader receiving address: {}", trader_address);
+    Ok(trader_address)
+}
+
+// Unlock an encrypted Miner wallet with --wallet-passphrase, if given, until
+// the returned guard is dropped.
+fn unlock_miner(app: &App) -> Result<Option<wallet::UnlockGuard<'_>>, AppError> {
+    app.cli
+        .wallet_passphrase
+        .as_deref()
+        .map(|pass| {
+            wallet::UnlockGuard::new(&app.miner_rpc, &app.miner_wallet, pass, UNLOCK_TIMEOUT_SECS)
+        })
+        .transpose()
+}
+
+// `--fund-trader`: pay `amount` to the Trader in one plain send, confirm it by
+// mining to `mining_address` and print the Trader's new balance. Unlike `send`
+// it extracts no transaction details and writes no report.
+fn run_fund_trader(app: &App, mining_address: &Address, amount: Amount) -> Result<(), AppError> {
+    let (cli, miner_rpc) = (&app.cli, &app.miner_rpc);
+    let trader_address = trader_receive_address(app)?;
+
+    let fee_rate = fees::choose_fee_rate(miner_rpc, cli.fee_rate, cli.conf_target)?;
+    let send_options = SendOptions {
+        fee_rate: Some(fee_rate),
+        replaceable: true,
+        subtract_fee: false,
+    };
+
+    // Check the balance up front, as `run_send` does
+    let required = amount + fees::fee_buffer(fee_rate, 1);
+    let spendable = miner_rpc.balance()?;
+    if spendable < required {
+        return Err(AppError::InsufficientFunds {
+            available: spendable,
+            requested: required,
+        });
+    }
+
+    let unlock_guard = unlock_miner(app)?;
+    let txid = miner_rpc
+        .send(&trader_address, amount, &send_options)
+        .and_then(|outcome| finish_send(miner_rpc, outcome))
+        .map_err(|e| match e {
+            AppError::Rpc(e) if wallet::is_insufficient_funds_error(&e) => {
+                insufficient_funds(miner_rpc, required, e)
+            }
+            e => e,
         })?;
-    println!("Trader receiving address: {}", trader_address);
-
-    // Send 20 BTC from Miner to Trader
-    let send_amount = Amount::from_btc(20.0)?;
-    let txid = miner_rpc.send_to_address(
-        &trader_address,
-        send_amount,
-        None, // comment
-        None, // comment_to
-        None, // subtract_fee_from_amount
-        None, // replaceable
-        None, // conf_target
-        None, // estimate_mode
+    drop(unlock_guard);
+    let hash = mining::confirm_transaction(miner_rpc, &txid, mining_address, cli.confirmations)?;
+    say!(
+        "Funded the Trader with {} {} in transaction {} (block {})",
+        report::format_amount(amount, cli.unit),
+        cli.unit,
+        txid,
+        hash
+    );
+    if cli.quiet {
+        println!("{}", txid);
+    }
+    wallet::report_balances(&[(&app.trader_wallet, &app.trader_rpc)], cli.unit)?;
+    Ok(())
+}
+
+// `send`: pay the Trader (and any extra recipients) from the funded Miner
+// wallet, confirm the transaction by mining to `mining_address` and write the
+// report. Returns the report, or `None` for a --split send.
+fn run_send(app: &App, mining_address: &Address) -> Result<Option<TxReport>, AppError> {
+    let (cli, settings) = (&app.cli, &app.settings);
+    let (rpc, miner_rpc, trader_rpc) = (&app.rpc, &app.miner_rpc, &app.trader_rpc);
+    // A sweep (--sweep or --sendall) sends the Miner's whole spendable balance
+    // instead of --amount
+    let sweep = cli.sweep || cli.sendall;
+    let send_amount = if sweep {
+        miner_rpc.balance()?
+    } else {
+        settings.amount
+    };
+    let total_send = if sweep { send_amount } else { app.total_send() };
+
+    // Confirming the send mines blocks, which only works on regtest
+    if !cli.dry_run {
+        mining::ensure_can_mine(settings.network)?;
+    }
+
+    let trader_address = trader_receive_address(app)?;
+
+    // Balances of both wallets before the transfer
+    say!("Balances before the send:");
+    wallet::report_balances(
+        &[
+            (&app.miner_wallet, miner_rpc),
+            (&app.trader_wallet, trader_rpc),
+        ],
+        cli.unit,
+    )?;
+
+    // Coins the Miner can spend right now (mature, confirmed UTXOs)
+    say!("Miner spendable UTXOs:");
+    wallet::print_utxos(&wallet::list_utxos(miner_rpc, 1)?, cli.unit);
+
+    // Decide on the fee rate up front so every send path uses the same one, and
+    // signal BIP 125 replaceability so the fee can be bumped later
+    let conf_target = if cli.confirm_target_blocks.is_empty() {
+        cli.conf_target
+    } else {
+        let previews = fees::preview_fee_rates(miner_rpc, &cli.confirm_target_blocks)?;
+        say!("Fee estimates by confirmation target:");
+        fees::print_fee_preview(&previews, 1 + cli.recipient.len());
+        pick_conf_target(cli.conf_target, cli.quiet)?
+    };
+    let fee_rate = fees::choose_fee_rate(miner_rpc, cli.fee_rate, conf_target)?;
+    let send_options = SendOptions {
+        fee_rate: Some(fee_rate),
+        replaceable: true,
+        subtract_fee: sweep || cli.fee_from_trader,
+    };
+
+    // Load-test mode: N separate equal payments, confirmed together in one block
+    if let Some(parts) = cli.split {
+        let unlock_guard = unlock_miner(app)?;
+        let txids = send_split(
+            miner_rpc,
+            trader_rpc,
+            send_amount,
+            parts,
+            settings.network,
+            cli.address_type,
+            &send_options,
+        )?;
+        drop(unlock_guard);
+        miner_rpc.mine(1, mining_address)?;
+        let total_fee = node::fees_paid(miner_rpc, &txids)?;
+        say!(
+            "Split send: {} of {} payments confirmed, total fee {} {}",
+            txids.len(),
+            parts,
+            report::format_amount(total_fee, cli.unit),
+            cli.unit
+        );
+        if cli.quiet {
+            for txid in &txids {
+                println!("{}", txid);
+            }
+        }
+        return Ok(None);
+    }
+    // Everything this transaction pays: the Trader first, then extra recipients
+    let mut payments = vec![Payment {
+        address: trader_address.clone(),
+        amount: send_amount,
+    }];
+    for recipient in &cli.recipient {
+        let address = tx::checked_address(recipient.address.clone(), settings.network)?;
+        if payments.iter().any(|p| p.address == address) {
+            return Err(AppError::InvalidArgument(format!(
+                "{} is paid more than once; use a single --recipient per address",
+                address
+            )));
+        }
+        payments.push(Payment {
+            address,
+            amount: recipient.amount,
+        });
+    }
+
+    // Make sure the Miner can actually cover the send and its fee before asking
+    // Core to build it, rather than failing deep inside the send RPC. A sweep
+    // or --fee-from-trader send pays its fee out of the amount sent.
+    let required = if send_options.subtract_fee {
+        total_send
+    } else {
+        total_send + fees::fee_buffer(fee_rate, payments.len())
+    };
+    let spendable = miner_rpc.balance()?;
+    if spendable < required {
+        return Err(AppError::InsufficientFunds {
+            available: spendable,
+            requested: required,
+        });
+    }
+
+    // Coin control and data outputs go through createrawtransaction; make sure
+    // a pinned UTXO exists before building anything
+    let template = tx::RawTxTemplate {
+        inputs: cli.from_utxo.into_iter().collect(),
+        op_return: cli.op_return.clone(),
+        locktime: cli.locktime,
+    };
+    if let Some(utxo) = &cli.from_utxo {
+        tx::ensure_spendable_utxo(miner_rpc, utxo)?;
+    }
+
+    let mut mempool_relatives = None;
+    let mut mempool = None;
+    let details = if cli.dry_run {
+        // Build and fund the transaction but never broadcast or confirm it
+        info!("DRY RUN: building the transaction without broadcasting it");
+        let funded = tx::fund_transaction(miner_rpc, &payments, &template, &send_options)?;
+        info!(
+            "DRY RUN: projected fee {} BTC, change at output {}",
+            funded.fee.to_btc(),
+            funded.change_position
+        );
+        check_fee_cap(funded.fee, cli.max_fee)?;
+        if send_options.subtract_fee {
+            payments[0].amount = tx::amount_after_fee(send_amount, funded.fee)?;
+        }
+        tx::simulate_tx_details(miner_rpc, &funded, &payments, settings.network)?
+    } else {
+        // Unlock an encrypted Miner wallet just for the send (and any fee bump)
+        let unlock_guard = unlock_miner(app)?;
+
+        // Send the requested amount (20 BTC by default) from Miner to Trader, paying
+        // any extra recipients from the same transaction, either directly or
+        // through the PSBT workflow or as a custom raw transaction
+        let use_sendall = cli.sendall && sendall_supported(rpc)?;
+        let send_once = || -> Result<Txid, AppError> {
+            Ok(if let Some(locktime) = template.locktime {
+                let raw_tx = tx::sign_raw(miner_rpc, &payments, &template, &send_options)?;
+                tx::broadcast_after_locktime(miner_rpc, &raw_tx, locktime, mining_address)?
+            } else if template.is_custom() {
+                tx::send_raw(miner_rpc, &payments, &template, &send_options)?
+            } else if cli.use_psbt {
+                tx::send_with_psbt(miner_rpc, &payments, &send_options)?
+            } else if use_sendall {
+                finish_send(
+                    miner_rpc,
+                    miner_rpc.send_all(&trader_address, &send_options)?,
+                )?
+            } else if payments.len() == 1 {
+                finish_send(
+                    miner_rpc,
+                    miner_rpc.send(&trader_address, send_amount, &send_options)?,
+                )?
+            } else {
+                finish_send(miner_rpc, miner_rpc.send_many(&payments, &send_options)?)?
+            })
+        };
+        // With --keep-going, transient RPC errors (see retry::is_transient) are retried
+        let attempts = if cli.keep_going { SEND_ATTEMPTS } else { 1 };
+        let txid = retry::retry_transient(attempts, SEND_RETRY_DELAY, send_once)
+            .inspect_err(|e| {
+                if matches!(e, AppError::Rpc(e) if wallet::is_locked_error(e)) {
+                    warn!(
+                        "The Miner wallet is encrypted and locked; \
+                         pass --wallet-passphrase to unlock it for the send"
+                    );
+                }
+            })
+            .map_err(|e| match e {
+                AppError::Rpc(e) if wallet::is_insufficient_funds_error(&e) => {
+                    insufficient_funds(miner_rpc, required, e)
+                }
+                e => e,
+            })?;
+
+        info!("Transaction sent with ID: {}", txid);
+
+        // Optionally replace it with a higher-fee version before it confirms
+        let sent_txid = txid;
+        let txid = match cli.bump_fee_rate {
+            Some(rate) => {
+                let bumped = tx::bump_fee(miner_rpc, &txid, rate)?;
+                info!("Fee bumped to {} sat/vB; replacement txid {}", rate, bumped);
+                bumped
+            }
+            None => txid,
+        };
+        drop(unlock_guard);
+
+        // A sweep or --fee-from-trader send paid its fee out of the Trader
+        // output, so look for what is left. bumpfee takes its extra fee from the
+        // change, so the Trader output still reflects the original fee.
+        if send_options.subtract_fee {
+            if let Some(fee) = miner_rpc.tx_fee(&sent_txid)? {
+                payments[0].amount = tx::amount_after_fee(send_amount, fee)?;
+            }
+        }
+
+        // Fetch the unconfirmed transaction from mempool
+        let mempool_entry = miner_rpc.mempool_entry(&txid)?;
+        say!(
+            "Mempool entry: {}",
+            serde_json::to_string_pretty(&mempool_entry)?
+        );
+        let relatives = tx::mempool_relatives(miner_rpc, &txid)?;
+        say!(
+            "Mempool ancestors: {} (fees {} BTC), descendants: {} (fees {} BTC)",
+            relatives.ancestor_count,
+            relatives.ancestor_fees.to_btc(),
+            relatives.descendant_count,
+            relatives.descendant_fees.to_btc()
+        );
+        mempool_relatives = Some(relatives);
+
+        // And the mempool as a whole, to judge how likely a quick confirmation is
+        let summary = tx::mempool_summary(miner_rpc, &txid)?;
+        say!(
+            "Mempool: {} transaction(s), {} bytes, min relay fee {} sat/vB, min fee {} sat/vB",
+            summary.size,
+            summary.bytes,
+            summary.min_relay_fee_sat_vb,
+            summary.mempool_min_fee_sat_vb
+        );
+        if summary.lower_fee_rate_count + summary.higher_fee_rate_count == 0 {
+            say!(
+                "Our transaction ({:.2} sat/vB) has no other fee rates to compete with",
+                summary.fee_rate_sat_vb
+            );
+        } else {
+            say!(
+                "Our transaction ({:.2} sat/vB) outbids {} and is outbid by {} transaction(s)",
+                summary.fee_rate_sat_vb,
+                summary.lower_fee_rate_count,
+                summary.higher_fee_rate_count
+            );
+        }
+        mempool = Some(summary);
+
+        // Mine blocks until the transaction has the requested confirmations (1 by
+        // default), or bury it under exactly --mine-blocks blocks. With
+        // --no-confirm it stays in the mempool and is reported unconfirmed.
+        let confirmation_block_hash = if cli.no_confirm {
+            info!("Leaving transaction {} unconfirmed in the mempool", txid);
+            None
+        } else {
+            let hash = match cli.mine_blocks {
+                Some(blocks) => mining::bury_transaction(miner_rpc, &txid, mining_address, blocks)?,
+                None => {
+                    let hash = mining::confirm_transaction(
+                        miner_rpc,
+                        &txid,
+                        mining_address,
+                        cli.confirmations,
+                    )?;
+                    // Check the block's own tx list rather than trusting it blindly
+                    node::containing_block(miner_rpc, &txid, hash)?
+                }
+            };
+            info!("Transaction confirmed in block: {}", hash);
+            Some(hash)
+        };
+
+        // Extract transaction details
+        tx::extract_tx_details(
+            miner_rpc,
+            &txid,
+            confirmation_block_hash.as_ref(),
+            &payments,
+            settings.network,
+        )?
+    };
+    // The fee actually paid must stay under the cap; a dry run checked its
+    // projected fee before simulating the details
+    if !cli.dry_run {
+        check_fee_cap(details.fee, cli.max_fee)?;
+    }
+
+    // Change was found by Miner ownership; the Trader output must likewise
+    // belong to the Trader wallet
+    if !details.trader_output_address.is_empty()
+        && !tx::wallet_owns(trader_rpc, &details.trader_output_address)?
+    {
+        return Err(AppError::InvalidState(format!(
+            "trader output {} is not owned by the Trader wallet",
+            details.trader_output_address
+        )));
+    }
+
+    // Optionally show the transaction as the node serialized it
+    if cli.show_raw && !details.simulated {
+        show_raw_transaction(miner_rpc, &details.txid, details.block_hash.as_ref())?;
+    }
+
+    // And after it, to show where the coins went
+    say!("Balances after the send:");
+    wallet::report_balances(
+        &[
+            (&app.miner_wallet, miner_rpc),
+            (&app.trader_wallet, trader_rpc),
+        ],
+        cli.unit,
     )?;
 
-    println!("Transaction sent with ID: {}", txid);
+    match details.fee_rate_sat_vb {
+        Some(rate) => info!(
+            "Fee: {} sat over {} vB = {:.2} sat/vB",
+            details.fee.to_sat(),
+            details.vsize.unwrap_or_default(),
+            rate
+        ),
+        None => info!("Fee: {} sat (fee rate unknown)", details.fee.to_sat()),
+    }
+    let mut report = TxReport::from(&details);
+    report.sweep = sweep;
+    report.fee_from_trader = cli.fee_from_trader;
+    report.requested_amount = Some(send_amount);
+    report.chosen_fee_rate_sat_vb = Some(fee_rate);
+    report.mempool_ancestor_count = mempool_relatives.map(|r| r.ancestor_count);
+    report.mempool_descendant_count = mempool_relatives.map(|r| r.descendant_count);
+    report.mempool = mempool;
+    if let Some(height) = details.block_height {
+        report.block_stats = tx::block_stats(miner_rpc, height)?;
+    }
+    if let Some(hash) = &details.block_hash {
+        report.block_times = Some(tx::block_times(miner_rpc, hash)?);
+    }
+    if !report.simulated {
+        if let Some(rate) = cli.bump_fee_rate {
+            report.chosen_fee_rate_sat_vb = Some(rate);
+        }
+    }
+
+    write_reports(app, &report)?;
 
-    // Fetch the unconfirmed transaction from mempool
-    let mempool_entry =
-        rpc.call::<serde_json::Value>("getmempoolentry", &[json!(txid.to_string())])?;
-    println!(
-        "Mempool entry: {}",
-        serde_json::to_string_pretty(&mempool_entry)?
+    // Keep a history of real sends next to the report (or at --run-log)
+    if !report.simulated {
+        let run_log = cli
+            .run_log
+            .clone()
+            .unwrap_or_else(|| settings.out.with_file_name("runs.log"));
+        report::append_run_log(
+            &run_log,
+            &report.txid,
+            report.transaction_fees,
+            miner_rpc.balance()?,
+        )?;
+        info!("Run recorded in {}", run_log.display());
+    }
+    Ok(Some(report))
+}
+
+// Print the raw hex of `txid` (looked up in `block_hash`, or the mempool) and
+// its full `decoderawtransaction` structure: version, inputs with scriptSig and
+// witness, outputs and locktime.
+fn show_raw_transaction(
+    rpc: &Node,
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+) -> Result<(), AppError> {
+    let hex = rpc.raw_transaction_hex(txid, block_hash)?;
+    say!("Raw transaction: {}", hex);
+    let decoded: serde_json::Value = rpc.decode_raw_transaction(&hex)?;
+    say!(
+        "Decoded transaction: {}",
+        serde_json::to_string_pretty(&decoded)?
     );
+    Ok(())
+}
+
+// Ask on the terminal which confirmation target to use after a fee preview,
+// with `default` (--conf-target) for an empty answer. Without a terminal, or
+// with --quiet, `default` is used without asking.
+fn pick_conf_target(default: u16, quiet: bool) -> Result<u16, AppError> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let stdin = std::io::stdin();
+    if quiet || !stdin.is_terminal() {
+        info!("Using confirmation target {} (--conf-target)", default);
+        return Ok(default);
+    }
+    loop {
+        print!("Confirmation target in blocks [{}]: ", default);
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            return Ok(default);
+        }
+        match answer.trim() {
+            "" => return Ok(default),
+            answer => match answer.parse::<u16>() {
+                Ok(target) if target > 0 => return Ok(target),
+                _ => println!("Enter a number of blocks of at least 1"),
+            },
+        }
+    }
+}
+
+// Fail with the fee and the --max-fee cap when `fee` is above `max_fee`.
+fn check_fee_cap(fee: Amount, max_fee: Amount) -> Result<(), AppError> {
+    if fee > max_fee {
+        return Err(AppError::FeeAboveCap { fee, max_fee });
+    }
+    Ok(())
+}
+
+// Turn Core's insufficient-funds error `e` for a send of `requested` into
+// AppError::InsufficientFunds with the wallet's current balance. If even the
+// balance cannot be read, the original RPC error is kept.
+fn insufficient_funds(rpc: &Node, requested: Amount, e: bitcoincore_rpc::Error) -> AppError {
+    match rpc.balance() {
+        Ok(available) => AppError::InsufficientFunds {
+            available,
+            requested,
+        },
+        Err(balance_error) => {
+            warn!("Could not read the balance: {}", balance_error);
+            AppError::Rpc(e)
+        }
+    }
+}
 
-    // Mine 1 block to confirm the transaction
-    let confirmation_blocks = rpc.generate_to_address(1, &mining_address)?;
-    let confirmation_block_hash = confirmation_blocks[0];
-    println!(
-        "Transaction confirmed in block: {}",
-        confirmation_block_hash
+// Print how the Miner balance reconciles with the coins created by the blocks
+// mined since `start_height` and what the send paid out (see
+// report::reconcile). Every block in that range is assumed to be ours, which
+// holds on a private regtest node.
+fn print_reconciliation(
+    app: &App,
+    start_height: u64,
+    start_balance: Amount,
+    report: &TxReport,
+) -> Result<(), AppError> {
+    let miner_rpc = &app.miner_rpc;
+    let created = mining::subsidy_between(start_height, miner_rpc.block_count()?);
+    // A dry run never left the wallet; a confirmed send's fee was mined back
+    let (sent, fees) = if report.simulated {
+        (Amount::ZERO, Amount::ZERO)
+    } else {
+        let sent = report.recipients.iter().map(|r| r.amount).sum();
+        (sent, report.transaction_fees)
+    };
+    let fees_collected = if report.block_hash.is_some() {
+        fees
+    } else {
+        Amount::ZERO
+    };
+    let final_balance = miner_rpc.total_balance()?;
+    let discrepancy = report::reconcile(
+        start_balance,
+        created,
+        sent,
+        fees,
+        fees_collected,
+        final_balance,
     );
+    let unit = app.cli.unit;
+    say!(
+        "Reconciliation ({}): {} start + {} created - {} sent - {} fees + {} fees mined back \
+         vs {} final balance, discrepancy {} sat",
+        unit,
+        report::format_amount(start_balance, unit),
+        report::format_amount(created, unit),
+        report::format_amount(sent, unit),
+        report::format_amount(fees, unit),
+        report::format_amount(fees_collected, unit),
+        report::format_amount(final_balance, unit),
+        discrepancy
+    );
+    if discrepancy != 0 {
+        warn!(
+            "Miner balance is off by {} sat from the reconciliation",
+            discrepancy
+        );
+    }
+    Ok(())
+}
 
-    // Extract transaction details
-    let raw_tx_info = rpc.get_raw_transaction_info(&txid, Some(&confirmation_block_hash))?;
-    let block_info = rpc.get_block_info(&confirmation_block_hash)?;
-    let block_height = block_info.height;
-
-    // Extract input details (from the first input)
-    let first_input = &raw_tx_info.vin[0];
-    let input_txid = first_input.txid.as_ref().unwrap();
-    let input_vout = first_input.vout.unwrap();
-
-    // Get the previous transaction to find input details
-    let prev_tx_info = rpc.get_raw_transaction_info(input_txid, None)?;
-    let input_output = &prev_tx_info.vout[input_vout as usize];
-    let miner_input_amount_sats = input_output.value.to_sat();
-    let miner_input_amount = input_output.value.to_btc();
-    let miner_input_address = input_output
-        .script_pub_key
-        .address
-        .as_ref()
-        .unwrap()
-        .clone()
-        .require_network(Network::Regtest)
-        .map_err(|e| {
-            bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(
-                format!("Address validation error: {}", e).into(),
-            ))
-        })?
-        .to_string();
-
-    // Extract output details
-    let mut trader_output_address = String::new();
-    let mut trader_output_amount = 0.0;
-    let mut trader_output_amount_sats = 0u64;
-    let mut miner_change_address = String::new();
-    let mut miner_change_amount = 0.0;
-    let mut miner_change_amount_sats = 0u64;
-
-    for output in &raw_tx_info.vout {
-        if let Some(ref address) = output.script_pub_key.address {
-            let addr_str = address
-                .clone()
-                .require_network(Network::Regtest)
-                .map_err(|e| {
-                    bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(
-                        format!("Address validation error: {}", e).into(),
-                    ))
-                })?
-                .to_string();
-            let amount = output.value.to_btc();
-            let amount_sats = output.value.to_sat();
-
-            // Check if this output goes to the trader (should be 20.0 BTC)
-            if addr_str == trader_address.to_string() {
-                trader_output_address = addr_str;
-                trader_output_amount = amount;
-                trader_output_amount_sats = amount_sats;
-            } else {
-                // This is the change output back to miner
-                miner_change_address = addr_str;
-                miner_change_amount = amount;
-                miner_change_amount_sats = amount_sats;
+// `report`: regenerate the report files for the confirmed Miner transaction
+// `txid`. The payments are taken from the wallet's own record of what it sent,
+// in output order, so the first one is reported as the Trader payment.
+fn run_report(app: &App, txid: &Txid) -> Result<(), AppError> {
+    let miner_rpc = &app.miner_rpc;
+    let wallet_tx = miner_rpc.tx_info(txid)?;
+    let block_hash = wallet_tx.info.blockhash.ok_or_else(|| {
+        AppError::InvalidState(format!("{} is not confirmed yet; nothing to report", txid))
+    })?;
+
+    let mut payments = Vec::new();
+    for detail in &wallet_tx.details {
+        if detail.category != bitcoincore_rpc::json::GetTransactionResultDetailCategory::Send {
+            continue;
+        }
+        let Some(address) = &detail.address else {
+            continue;
+        };
+        payments.push(Payment {
+            address: tx::checked_address(address.clone(), app.settings.network)?,
+            amount: Amount::from_sat(detail.amount.to_sat().unsigned_abs()),
+        });
+    }
+    if payments.is_empty() {
+        return Err(AppError::InvalidState(format!(
+            "{} does not pay anyone from the Miner wallet",
+            txid
+        )));
+    }
+
+    let details = tx::extract_tx_details(
+        miner_rpc,
+        txid,
+        Some(&block_hash),
+        &payments,
+        app.settings.network,
+    )?;
+    let mut report = TxReport::from(&details);
+    report.block_times = Some(tx::block_times(miner_rpc, &block_hash)?);
+    write_reports(app, &report)
+}
+
+// The txid of an existing out.txt (or --out) in any --format, if the Miner
+// wallet still knows that transaction. A missing file is `None`; an unreadable
+// txid or one the wallet has never seen is only warned about, and the run
+// then goes ahead as usual.
+fn existing_report_txid(app: &App) -> Result<Option<Txid>, AppError> {
+    let out = &app.settings.out;
+    let contents = match std::fs::read_to_string(out) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let Some(txid) = report::report_txid(&contents) else {
+        warn!("{} has no readable txid; running anyway", out.display());
+        return Ok(None);
+    };
+    match app.miner_rpc.tx_info(&txid) {
+        Ok(_) => Ok(Some(txid)),
+        Err(e) => {
+            warn!(
+                "Transaction {} from {} is unknown to the Miner wallet ({}); running anyway",
+                txid,
+                out.display(),
+                e
+            );
+            Ok(None)
+        }
+    }
+}
+
+// Write `report` to out.txt (or --out) and as JSON next to it.
+fn write_reports(app: &App, report: &TxReport) -> Result<(), AppError> {
+    let out = &app.settings.out;
+
+    // Write data to the report file (../out.txt by default) in --format
+    report::write_report_as(out, report, app.cli.format, app.cli.unit)?;
+    // Read a positional report straight back so a truncated or misordered
+    // file fails the run
+    if app.cli.format == report::Format::Text {
+        report::verify_report(out, report, app.cli.unit)?;
+    }
+
+    // Write the same data as structured JSON next to it (out.json by default)
+    let json_path = out.with_extension("json");
+    serde_json::to_writer_pretty(File::create(&json_path)?, report)?;
+    info!("Structured report written to {}", json_path.display());
+
+    if report.simulated {
+        info!(
+            "DRY RUN: simulated transaction details written to {}",
+            out.display()
+        );
+    } else {
+        info!("Transaction details written to {}", out.display());
+    }
+    // With --quiet this is the only line on stdout
+    if app.cli.quiet {
+        println!("{}", report.txid);
+    }
+    Ok(())
+}
+
+// Run the subcommand, or without one the whole flow: mine, send, confirm and
+// report.
+fn run_command(app: &App) -> Result<(), AppError> {
+    match &app.cli.command {
+        None | Some(Command::Mine) if app.cli.generate_only => {
+            // run_mine already prints the Miner balance
+            run_mine(app, &app.mining_address()?)?;
+        }
+        Some(_) if app.cli.generate_only => {
+            return Err(AppError::InvalidArgument(
+                "--generate-only only mines; it cannot be combined with send or report".to_owned(),
+            ));
+        }
+        None if app.cli.fund_trader.is_some() => {
+            let mining_address = app.mining_address()?;
+            run_mine(app, &mining_address)?;
+            run_fund_trader(app, &mining_address, app.cli.fund_trader.unwrap())?;
+        }
+        Some(_) if app.cli.fund_trader.is_some() => {
+            return Err(AppError::InvalidArgument(
+                "--fund-trader runs its own mine and send; it cannot be combined with a subcommand"
+                    .to_owned(),
+            ));
+        }
+        None => {
+            let mining_address = app.mining_address()?;
+            let start_height = app.miner_rpc.block_count()?;
+            let start_balance = app.miner_rpc.total_balance()?;
+            run_mine(app, &mining_address)?;
+            if let Some(report) = run_send(app, &mining_address)? {
+                print_reconciliation(app, start_height, start_balance, &report)?;
             }
         }
+        Some(Command::Mine) => run_mine(app, &app.mining_address()?)?,
+        Some(Command::Send) => {
+            run_send(app, &app.mining_address()?)?;
+        }
+        Some(Command::Report { txid }) => run_report(app, txid)?,
+    }
+    Ok(())
+}
+
+// Partial status after Ctrl-C stopped mining.
+fn print_interrupted_status(app: &App, mined_blocks: usize) {
+    say!("Interrupted after mining {} block(s)", mined_blocks);
+    let status = app.miner_rpc.block_count().and_then(|height| {
+        say!("Chain height: {}", height);
+        wallet::report_balances(&[(&app.miner_wallet, &app.miner_rpc)], app.cli.unit)
+    });
+    if let Err(e) = status {
+        warn!("Could not read the chain status: {}", e);
+    }
+}
+
+// Exit with the code of the error category (see error::AppError::exit_code)
+// so scripts can react to each kind of failure.
+fn main() {
+    if let Err(e) = run() {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), AppError> {
+    // Log to stderr, honouring RUST_LOG (e.g. RUST_LOG=warn to quiet CI runs);
+    // stdout is reserved for actual results
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    // Parse command-line options, connect and set up both wallets
+    // clap exits with 2 on a usage error, which we use for connection
+    // failures; report bad options with the generic 1 instead
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        if e.use_stderr() {
+            let _ = e.print();
+            std::process::exit(1);
+        }
+        e.exit()
+    });
+    report::set_quiet(cli.quiet);
+    let app = setup(cli)?;
+
+    // Ctrl-C while mining the funding blocks stops after the current batch and
+    // a second one exits at once; outside that phase the first one exits, so
+    // nothing is broadcast after it
+    if let Err(e) = ctrlc::set_handler(|| {
+        if !mining::mining_in_progress() || mining::stop_requested() {
+            std::process::exit(error::EXIT_INTERRUPTED);
+        }
+        warn!("Interrupted; stopping after the current mining batch (Ctrl-C again to quit now)");
+        mining::request_stop();
+    }) {
+        warn!("Could not install the Ctrl-C handler: {}", e);
+    }
+
+    // Optionally unload both wallets again when we exit, however we exit
+    let mut unload_guard = app
+        .cli
+        .unload_on_exit
+        .then(|| wallet::UnloadGuard::new(&app.rpc, &[&app.miner_wallet, &app.trader_wallet]));
+
+    // --list-labels is a read-only diagnostic: show what each wallet has under
+    // which label (e.g. "Mining Reward", "Received") and stop there
+    if app.cli.list_labels {
+        wallet::print_labels(&app.miner_wallet, &app.miner_rpc)?;
+        wallet::print_labels(&app.trader_wallet, &app.trader_rpc)?;
+        return Ok(());
+    }
+
+    // With --skip-if-exists, a report from an earlier run whose transaction the
+    // Miner wallet still knows ends the run before anything is mined or sent
+    if app.cli.skip_if_exists && !matches!(app.cli.command, Some(Command::Report { .. })) {
+        if let Some(txid) = existing_report_txid(&app)? {
+            info!(
+                "{} already reports transaction {}; skipping this run",
+                app.settings.out.display(),
+                txid
+            );
+            println!("{}", txid);
+            return Ok(());
+        }
     }
 
-    // Calculate transaction fees using satoshis for precision
-    let transaction_fees_sats =
-        miner_input_amount_sats - trader_output_amount_sats - miner_change_amount_sats;
-    let transaction_fees = Amount::from_sat(transaction_fees_sats).to_btc();
-
-    // Write data to ../out.txt
-    let mut file = File::create("../out.txt")?;
-    writeln!(file, "{}", txid)?;
-    writeln!(file, "{}", miner_input_address)?;
-    writeln!(file, "{}", miner_input_amount)?;
-    writeln!(file, "{}", trader_output_address)?;
-    writeln!(file, "{}", trader_output_amount)?;
-    writeln!(file, "{}", miner_change_address)?;
-    writeln!(file, "{}", miner_change_amount)?;
-    writeln!(file, "{}", transaction_fees)?;
-    writeln!(file, "{}", block_height)?;
-    writeln!(file, "{}", confirmation_block_hash)?;
-
-    println!("Transaction details written to ../out.txt");
-    println!("Program completed successfully!");
+    match run_command(&app) {
+        // After Ctrl-C: show how far we got and unload the wallets even
+        // without --unload-on-exit, so the node is not left half set up
+        Err(AppError::Interrupted { mined_blocks }) => {
+            print_interrupted_status(&app, mined_blocks);
+            unload_guard
+                .get_or_insert_with(|| {
+                    wallet::UnloadGuard::new(&app.rpc, &[&app.miner_wallet, &app.trader_wallet])
+                })
+                .unload();
+            return Err(AppError::Interrupted { mined_blocks });
+        }
+        result => result?,
+    }
+    info!("Program completed successfully!");
 
     Ok(())
 }
@@ -0,0 +1,567 @@
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Network, Txid};
+use bitcoincore_rpc::json::{GetChainTipsResultStatus, GetChainTipsResultTip};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{AppError, Result};
+use crate::node::{self, Node, NodeApi};
+use crate::retry;
+
+// Coinbase outputs can only be spent once they are this many blocks deep.
+pub const COINBASE_MATURITY: u64 = 100;
+
+// Blocks per `generatetoaddress` call while mining towards maturity, so progress
+// can be reported along the way.
+pub const MINING_BATCH: u64 = 10;
+
+// Set by the Ctrl-C handler; the mining loops check it between batches.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Ask the mining loops to stop after the current batch.
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::Relaxed)
+}
+
+// Set while the funding blocks are mined (see `MiningPhase`): only then does
+// Ctrl-C wait for the current batch; anywhere else it exits at once.
+static MINING: AtomicBool = AtomicBool::new(false);
+
+// Marks the mining phase for the Ctrl-C handler until dropped.
+pub struct MiningPhase(());
+
+impl MiningPhase {
+    pub fn start() -> Self {
+        MINING.store(true, Ordering::Relaxed);
+        MiningPhase(())
+    }
+}
+
+impl Drop for MiningPhase {
+    fn drop(&mut self) {
+        MINING.store(false, Ordering::Relaxed);
+    }
+}
+
+pub fn mining_in_progress() -> bool {
+    MINING.load(Ordering::Relaxed)
+}
+
+// AppError::Interrupted once a stop was requested, after `mined_blocks` blocks.
+pub fn check_stop(mined_blocks: usize) -> Result<()> {
+    if stop_requested() {
+        return Err(AppError::Interrupted { mined_blocks });
+    }
+    Ok(())
+}
+
+// Tries per `generate_blocks` call, and the pause between them.
+const GENERATE_ATTEMPTS: u32 = 3;
+const GENERATE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// `generatetoaddress` only produces blocks on regtest, so refuse to start any
+// mining step against another network instead of failing halfway through.
+// Signet gets its own message: its blocks must satisfy the signet challenge,
+// and neither `generatetoaddress` nor `generateblock` can sign them, so they
+// have to come from an external signer such as Core's `contrib/signet/miner`.
+pub fn ensure_can_mine(network: Network) -> Result<()> {
+    match network {
+        Network::Regtest => Ok(()),
+        Network::Signet => Err(AppError::InvalidState(
+            "cannot mine on signet: blocks must be signed for the signet challenge; \
+             mine them with an external signer (e.g. Bitcoin Core's contrib/signet/miner) \
+             instead; --dry-run previews a send without mining"
+                .to_string(),
+        )),
+        _ => Err(AppError::InvalidState(format!(
+            "mining is only supported on regtest, but --network is {}",
+            network
+        ))),
+    }
+}
+
+// `generatetoaddress` that makes sure `count` blocks were really mined. A short
+// result (fewer hashes than asked for) is topped up by requesting only the
+// missing blocks, and transient errors (see retry::is_transient) are retried;
+// each retry logs a warning. Gives up after GENERATE_ATTEMPTS tries.
+pub fn generate_blocks(rpc: &Node, count: u64, addr: &Address) -> Result<Vec<BlockHash>> {
+    let mut hashes = Vec::with_capacity(count as usize);
+    let mut attempt = 1;
+    loop {
+        let missing = count - hashes.len() as u64;
+        match rpc.generate_to_address(missing, addr) {
+            Ok(mined) => {
+                hashes.extend(mined);
+                if hashes.len() as u64 >= count {
+                    return Ok(hashes);
+                }
+                if attempt >= GENERATE_ATTEMPTS {
+                    return Err(AppError::InvalidState(format!(
+                        "generatetoaddress mined only {} of {} blocks after {} attempts",
+                        hashes.len(),
+                        count,
+                        attempt
+                    )));
+                }
+                warn!(
+                    "generatetoaddress mined only {} of {} blocks (attempt {}/{}); retrying",
+                    hashes.len(),
+                    count,
+                    attempt,
+                    GENERATE_ATTEMPTS
+                );
+            }
+            Err(AppError::Rpc(e)) if attempt < GENERATE_ATTEMPTS && retry::is_transient(&e) => {
+                warn!(
+                    "Transient error mining blocks (attempt {}/{}): {}; retrying in {:?}",
+                    attempt, GENERATE_ATTEMPTS, e, GENERATE_RETRY_DELAY
+                )
+            }
+            Err(e) => return Err(e),
+        }
+        thread::sleep(GENERATE_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+// Blocks to mine to take the chain from `height` to `target_height`; an error
+// if the chain is already past the target.
+fn blocks_to_height(height: u64, target_height: u64) -> Result<u64> {
+    target_height.checked_sub(height).ok_or_else(|| {
+        AppError::InvalidState(format!(
+            "chain is already at height {}, past target height {}",
+            height, target_height
+        ))
+    })
+}
+
+// Mine until the chain tip is at `target_height` (e.g. to reach a timelock),
+// rather than mining a fixed number of blocks. Nothing is mined if the tip is
+// already there.
+pub fn mine_until_height(rpc: &Node, addr: &Address, target_height: u64) -> Result<()> {
+    let height = rpc.block_count()?;
+    let count = blocks_to_height(height, target_height)?;
+    if count > 0 {
+        rpc.mine(count, addr)?;
+    }
+    info!("Mined {} block(s) to reach height {}", count, target_height);
+    Ok(())
+}
+
+// Reports mining progress either as an indicatif bar or as plain log lines.
+pub enum MiningProgress {
+    Bar(ProgressBar),
+    Log,
+}
+
+impl MiningProgress {
+    // Use a bar only when asked to and stdout is an interactive terminal.
+    pub fn new(enabled: bool, target: u64) -> Self {
+        if enabled && io::stdout().is_terminal() {
+            let bar = ProgressBar::new(target);
+            bar.set_style(
+                ProgressStyle::with_template("{bar:40} {pos}/{len} blocks  {msg}")
+                    .expect("valid progress template"),
+            );
+            MiningProgress::Bar(bar)
+        } else {
+            MiningProgress::Log
+        }
+    }
+
+    pub fn update(&self, mined: usize, balance: Amount) {
+        match self {
+            MiningProgress::Bar(bar) => {
+                bar.set_position(mined as u64);
+                bar.set_message(format!("balance {}", balance));
+            }
+            MiningProgress::Log => info!("After {} blocks, Miner balance: {}", mined, balance),
+        }
+    }
+
+    // Mining past the initial target means the bar needs room for one more block.
+    pub fn extend(&self, blocks: u64) {
+        if let MiningProgress::Bar(bar) = self {
+            bar.inc_length(blocks);
+        }
+    }
+
+    pub fn finish(&self) {
+        if let MiningProgress::Bar(bar) = self {
+            bar.finish();
+        }
+    }
+}
+
+// Blocks between subsidy halvings on regtest (mainnet uses 210,000).
+pub const REGTEST_HALVING_INTERVAL: u64 = 150;
+
+// New coins created by the regtest block at `height`: 50 BTC, halved every
+// REGTEST_HALVING_INTERVAL blocks, down to nothing after 64 halvings.
+pub fn block_subsidy(height: u64) -> Amount {
+    let halvings = height / REGTEST_HALVING_INTERVAL;
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(Amount::from_int_btc(50).to_sat() >> halvings)
+}
+
+// Total subsidy of the blocks above `from_height` up to and including
+// `to_height`.
+pub fn subsidy_between(from_height: u64, to_height: u64) -> Amount {
+    (from_height + 1..=to_height).map(block_subsidy).sum()
+}
+
+// Confirmations an output created in block `created_height` has when spent by a
+// transaction in block `spending_height`. Consensus lets a coinbase output be
+// spent once this reaches COINBASE_MATURITY.
+pub fn confirmations_when_spent(created_height: u64, spending_height: u64) -> u64 {
+    spending_height.saturating_sub(created_height)
+}
+
+// Chain height at which the coinbase reward of the block at `coinbase_height`
+// becomes spendable: the wallet counts it once it has COINBASE_MATURITY + 1
+// confirmations, i.e. once the tip is COINBASE_MATURITY blocks above it.
+pub fn first_spendable_height(coinbase_height: u64) -> u64 {
+    coinbase_height + COINBASE_MATURITY
+}
+
+// Default for --funding-blocks: just enough for the first reward to mature.
+pub const DEFAULT_FUNDING_BLOCKS: u64 = COINBASE_MATURITY + 1;
+
+// Blocks to mine on top of `funding_blocks` so that the first of them matures:
+// none once there are COINBASE_MATURITY + 1 of them already.
+pub fn maturity_blocks(funding_blocks: u64) -> u64 {
+    (COINBASE_MATURITY + 1).saturating_sub(funding_blocks)
+}
+
+// Mine `funding_blocks` blocks plus whatever is needed for the first of them to
+// mature (see `maturity_blocks`), in batches, then keep mining single blocks,
+// each of which matures one more coinbase, until the wallet behind `rpc` has at
+// least `target_spendable` available. Each batch (or single block) pays the
+// next of `addrs` in turn, so several addresses can share the rewards. Each
+// batch is followed by a `poll_interval` pause before the balance is read. With
+// `progress` set, a progress bar is shown on interactive terminals. Returns
+// every block mined.
+pub fn mine_to_maturity(
+    rpc: &Node,
+    addrs: &[Address],
+    funding_blocks: u64,
+    target_spendable: Amount,
+    poll_interval: Duration,
+    progress: bool,
+) -> Result<Vec<BlockHash>> {
+    assert!(!addrs.is_empty(), "at least one reward address is needed");
+    let mut reward_addrs = addrs.iter().cycle();
+    let maturity = maturity_blocks(funding_blocks);
+    let initial_blocks = funding_blocks + maturity;
+    let progress = MiningProgress::new(progress, initial_blocks);
+
+    let mut hashes = Vec::new();
+    while (hashes.len() as u64) < initial_blocks {
+        check_stop(hashes.len()).inspect_err(|_| progress.finish())?;
+        let batch = MINING_BATCH.min(initial_blocks - hashes.len() as u64);
+        let addr = reward_addrs.next().expect("cycle over a non-empty slice");
+        hashes.extend(rpc.mine(batch, addr)?);
+        thread::sleep(poll_interval);
+        progress.update(hashes.len(), rpc.balance()?);
+    }
+
+    let mut balance = rpc.balance()?;
+    let mut top_up = 0;
+    while balance < target_spendable {
+        check_stop(hashes.len()).inspect_err(|_| progress.finish())?;
+        progress.extend(1);
+        let addr = reward_addrs.next().expect("cycle over a non-empty slice");
+        hashes.extend(rpc.mine(1, addr)?);
+        thread::sleep(poll_interval);
+        top_up += 1;
+        balance = rpc.balance()?;
+        progress.update(hashes.len(), balance);
+    }
+    progress.finish();
+
+    log_mining_summary(funding_blocks, top_up, target_spendable);
+    Ok(hashes)
+}
+
+// Log what the blocks of a `mine_to_maturity` run were for: the
+// `funding_blocks`, the ones that matured the first reward, and the `top_up`
+// blocks mined after that to reach `target_spendable`.
+pub fn log_mining_summary(funding_blocks: u64, top_up: u64, target_spendable: Amount) {
+    info!("Mined {} funding block(s)", funding_blocks);
+    info!(
+        "Mined {} extra block(s) to mature the first reward",
+        maturity_blocks(funding_blocks)
+    );
+    if top_up > 0 {
+        info!(
+            "Mined {} more block(s) to reach a spendable balance of {}",
+            top_up, target_spendable
+        );
+    }
+}
+
+// Tips that compete for the chain: the active tip and fully validated forks.
+// More than one means another process is mining a rival branch.
+fn competing_tips(tips: &[GetChainTipsResultTip]) -> usize {
+    tips.iter()
+        .filter(|tip| {
+            matches!(
+                tip.status,
+                GetChainTipsResultStatus::Active | GetChainTipsResultStatus::ValidFork
+            )
+        })
+        .count()
+}
+
+// Print every tip from `getchaintips` with its status and branch length, and
+// warn when a valid fork competes with the active chain, since a reorg could
+// undo the confirmations the flow counts on. Read-only.
+pub fn check_chain_tips(rpc: &Node) -> Result<()> {
+    let tips = rpc.chain_tips()?;
+    say!("{:>8} {:>14} {:>10}  hash", "Height", "Status", "Branch");
+    for tip in &tips {
+        let status = serde_json::to_value(tip.status)?;
+        say!(
+            "{:>8} {:>14} {:>10}  {}",
+            tip.height,
+            status.as_str().unwrap_or_default(),
+            tip.branch_length,
+            tip.hash
+        );
+    }
+    let competing = competing_tips(&tips);
+    if competing > 1 {
+        warn!(
+            "{} competing chain tips (active and valid-fork); a reorg may invalidate confirmations",
+            competing
+        );
+    } else {
+        info!("Single valid chain tip; no competing forks");
+    }
+    Ok(())
+}
+
+// Mine one block at a time until `txid` has at least `confs` confirmations, as
+// reported by the wallet behind `rpc`. Every block is checked with
+// `get_transaction`: a transaction still unconfirmed after a block was mined
+// never made it in (dropped, replaced or non-final) and is an error rather
+// than an endless loop. Returns the hash of the block that first included the
+// transaction (not the tip we stopped at).
+pub fn confirm_transaction<N: NodeApi + ?Sized>(
+    rpc: &N,
+    txid: &Txid,
+    mining_addr: &Address,
+    confs: u32,
+) -> Result<BlockHash> {
+    loop {
+        let block = rpc.mine(1, mining_addr)?.remove(0);
+        let info = rpc.tx_info(txid)?.info;
+        if info.confirmations < 1 {
+            return Err(AppError::InvalidState(format!(
+                "transaction {} is not in block {} ({} confirmations); \
+                 it was dropped or replaced",
+                txid, block, info.confirmations
+            )));
+        }
+        info!(
+            "Transaction {} has {} confirmation(s)",
+            txid, info.confirmations
+        );
+        if info.confirmations >= confs as i32 {
+            return info.blockhash.ok_or_else(|| {
+                AppError::InvalidState(format!("confirmed transaction {} has no block hash", txid))
+            });
+        }
+    }
+}
+
+// Mine `blocks` blocks at once on top of the unconfirmed `txid`, burying it,
+// and return the block that contains it (see node::containing_block), usually
+// the first of the batch rather than the tip. Errors if none of the new blocks
+// includes the transaction.
+pub fn bury_transaction<N: NodeApi + ?Sized>(
+    rpc: &N,
+    txid: &Txid,
+    mining_addr: &Address,
+    blocks: u64,
+) -> Result<BlockHash> {
+    let mined = rpc.mine(blocks, mining_addr)?;
+    let info = rpc.tx_info(txid)?.info;
+    info!(
+        "Mined {} block(s); transaction {} has {} confirmation(s)",
+        mined.len(),
+        txid,
+        info.confirmations
+    );
+    let not_mined = || {
+        AppError::InvalidState(format!(
+            "transaction {} is not in any of the {} blocks just mined",
+            txid,
+            mined.len()
+        ))
+    };
+    let first = *mined.first().ok_or_else(not_mined)?;
+    let hash = node::containing_block(rpc, txid, first)?;
+    if !mined.contains(&hash) {
+        return Err(not_mined());
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> BlockHash {
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+        BlockHash::from_byte_array([byte; 32])
+    }
+
+    #[test]
+    fn confirming_needs_the_transaction_in_the_block_just_mined() {
+        use crate::node::mock::{wallet_tx, MockNode};
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+        let addr = Address::p2wsh(
+            bitcoincore_rpc::bitcoin::Script::from_bytes(&[1]),
+            Network::Regtest,
+        );
+        let confirmed = Txid::from_byte_array([0xaa; 32]);
+        let dropped = Txid::from_byte_array([0xbb; 32]);
+        let mut node = MockNode::default();
+        let mut tx = wallet_tx(confirmed, -20.0, Some(-0.0000141));
+        tx["blockhash"] = serde_json::json!(hash(0).to_string());
+        node.transactions.insert(confirmed, tx);
+        let mut tx = wallet_tx(dropped, -20.0, Some(-0.0000141));
+        tx["confirmations"] = serde_json::json!(0);
+        node.transactions.insert(dropped, tx);
+
+        assert_eq!(
+            confirm_transaction(&node, &confirmed, &addr, 1).unwrap(),
+            hash(0)
+        );
+        // Still unconfirmed after a block: an error, not another block
+        assert!(matches!(
+            confirm_transaction(&node, &dropped, &addr, 1),
+            Err(AppError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn buried_transaction_reports_its_containing_block_not_the_tip() {
+        use crate::node::mock::{block, wallet_tx, MockNode};
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+        // The mock mines blocks 0, 1 and 2; the transaction went into block 0
+        let ours = Txid::from_byte_array([0xaa; 32]);
+        let mut node = MockNode::default();
+        node.transactions
+            .insert(ours, wallet_tx(ours, -20.0, Some(-0.0000141)));
+        node.blocks
+            .insert(hash(0), block(0, &[ours], Some(hash(1))));
+        node.blocks.insert(hash(1), block(1, &[], Some(hash(2))));
+        node.blocks.insert(hash(2), block(2, &[], None));
+        let addr = Address::p2wsh(
+            bitcoincore_rpc::bitcoin::Script::from_bytes(&[1]),
+            Network::Regtest,
+        );
+
+        assert_eq!(bury_transaction(&node, &ours, &addr, 3).unwrap(), hash(0));
+        // A transaction none of the mined blocks holds is an error
+        let other = Txid::from_byte_array([0xbb; 32]);
+        node.transactions
+            .insert(other, wallet_tx(other, -1.0, Some(-0.0000141)));
+        assert!(matches!(
+            bury_transaction(&node, &other, &addr, 3),
+            Err(AppError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn mining_to_a_height_mines_the_difference_and_never_backwards() {
+        assert_eq!(blocks_to_height(101, 150).unwrap(), 49);
+        assert_eq!(blocks_to_height(150, 150).unwrap(), 0);
+        assert!(matches!(
+            blocks_to_height(151, 150),
+            Err(AppError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn coinbase_input_is_spent_with_at_least_maturity_confirmations() {
+        // Mined at height 1; the wallet spends it once the tip reaches
+        // first_spendable_height, so the spend lands in the block after that
+        let coinbase_height = 1;
+        let spending_height = first_spendable_height(coinbase_height) + 1;
+
+        let confirmations = confirmations_when_spent(coinbase_height, spending_height);
+
+        assert!(confirmations >= COINBASE_MATURITY);
+        // Consensus allows the spend exactly COINBASE_MATURITY blocks later
+        assert_eq!(confirmations_when_spent(1, 101), COINBASE_MATURITY);
+    }
+
+    #[test]
+    fn only_active_and_valid_fork_tips_compete() {
+        let tip = |byte: u8, status| GetChainTipsResultTip {
+            height: 200,
+            hash: hash(byte),
+            branch_length: byte as usize,
+            status,
+        };
+        let mut tips = vec![
+            tip(0, GetChainTipsResultStatus::Active),
+            tip(1, GetChainTipsResultStatus::Invalid),
+            tip(2, GetChainTipsResultStatus::HeadersOnly),
+        ];
+        assert_eq!(competing_tips(&tips), 1);
+
+        tips.push(tip(3, GetChainTipsResultStatus::ValidFork));
+        assert_eq!(competing_tips(&tips), 2);
+    }
+
+    #[test]
+    fn mining_is_refused_outside_regtest() {
+        assert!(ensure_can_mine(Network::Regtest).is_ok());
+        for network in [Network::Signet, Network::Testnet, Network::Bitcoin] {
+            assert!(matches!(
+                ensure_can_mine(network),
+                Err(AppError::InvalidState(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn regtest_subsidy_halves_every_150_blocks() {
+        assert_eq!(block_subsidy(1), Amount::from_int_btc(50));
+        assert_eq!(block_subsidy(149), Amount::from_int_btc(50));
+        assert_eq!(block_subsidy(150), Amount::from_int_btc(25));
+        assert_eq!(block_subsidy(300), Amount::from_sat(1_250_000_000));
+        assert_eq!(block_subsidy(150 * 64), Amount::ZERO);
+        // Blocks 148..=151: two at 50 BTC, two at 25 BTC
+        assert_eq!(subsidy_between(147, 151), Amount::from_int_btc(150));
+    }
+
+    #[test]
+    fn maturity_wait_covers_only_what_funding_blocks_lack() {
+        assert_eq!(maturity_blocks(DEFAULT_FUNDING_BLOCKS), 0);
+        assert_eq!(maturity_blocks(1), 100);
+        assert_eq!(maturity_blocks(40), 61);
+        assert_eq!(maturity_blocks(250), 0);
+    }
+
+    #[test]
+    fn coinbase_matures_one_hundred_blocks_later() {
+        assert_eq!(first_spendable_height(1), 101);
+        assert_eq!(first_spendable_height(250), 350);
+    }
+}
@@ -0,0 +1,912 @@
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Txid};
+use bitcoincore_rpc::json::{
+    AddressType, EstimateSmartFeeResult, FinalizePsbtResult, FundRawTransactionResult,
+    GetBalancesResult, GetBlockHeaderResult, GetBlockResult, GetBlockchainInfoResult,
+    GetChainTipsResult, GetMempoolInfoResult, GetNetworkInfoResult, GetRawTransactionResult,
+    GetTransactionResult, ListUnspentResultEntry, SignRawTransactionResult,
+    WalletCreateFundedPsbtResult, WalletProcessPsbtResult,
+};
+use bitcoincore_rpc::{Client, RawTx, RpcApi};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::error::{AppError, Result};
+use crate::mining;
+use crate::tx::{Payment, SendOptions};
+
+// The node operations the flow relies on. `Node` implements them over RPC;
+// tests can implement them with canned responses instead (see `MockNode`).
+pub trait NodeApi {
+    // Mine `count` blocks to `addr`.
+    fn mine(&self, count: u64, addr: &Address) -> Result<Vec<BlockHash>>;
+
+    // Spendable balance of the wallet (`getbalance`).
+    fn balance(&self) -> Result<Amount>;
+
+    // Send `amount` to `addr`: broadcast, or left for the caller to sign.
+    fn send(&self, addr: &Address, amount: Amount, options: &SendOptions) -> Result<SendOutcome>;
+
+    // The wallet's view of `txid` (`gettransaction`).
+    fn tx_info(&self, txid: &Txid) -> Result<GetTransactionResult>;
+
+    // The block `hash` (`getblock`).
+    fn block_info(&self, hash: &BlockHash) -> Result<GetBlockResult>;
+
+    // Height of the chain tip.
+    fn block_count(&self) -> Result<u64>;
+
+    // `getrawtransaction` (verbose) for `txid`, in `block_hash` or the mempool.
+    fn raw_transaction_info(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<GetRawTransactionResult>;
+
+    // The raw hex of every one of `txids`, in order.
+    fn raw_transaction_hexes(&self, txids: &[Txid]) -> Result<Vec<String>>;
+
+    // What the wallet knows about `addr` (`getaddressinfo`).
+    fn address_info(&self, addr: &str) -> Result<AddressInfo>;
+
+    // The fee the wallet paid for `txid`, or `None` if it did not send it. The
+    // wallet reports the fee of its own sends as a negative amount.
+    fn tx_fee(&self, txid: &Txid) -> Result<Option<Amount>> {
+        Ok(self
+            .tx_info(txid)?
+            .fee
+            .map(|fee| Amount::from_sat(fee.to_sat().unsigned_abs())))
+    }
+}
+
+// Total fee the wallet paid for `txids`; transactions it did not send count
+// as zero.
+pub fn fees_paid<N: NodeApi + ?Sized>(node: &N, txids: &[Txid]) -> Result<Amount> {
+    let mut total = Amount::ZERO;
+    for txid in txids {
+        total += node.tx_fee(txid)?.unwrap_or(Amount::ZERO);
+    }
+    Ok(total)
+}
+
+// The block that really contains `txid`: `from` if its `tx` list has the
+// transaction, otherwise the first block after it on the active chain that does
+// (another process may have mined a block before ours). Errors once the search
+// passes the tip without finding it.
+pub fn containing_block<N: NodeApi + ?Sized>(
+    node: &N,
+    txid: &Txid,
+    from: BlockHash,
+) -> Result<BlockHash> {
+    let mut hash = from;
+    loop {
+        let block = node.block_info(&hash)?;
+        if block.tx.contains(txid) {
+            if hash != from {
+                warn!(
+                    "Transaction {} is in block {} (height {}), not in block {}",
+                    txid, hash, block.height, from
+                );
+            }
+            return Ok(hash);
+        }
+        hash = block.nextblockhash.ok_or_else(|| {
+            AppError::InvalidState(format!(
+                "transaction {} is in neither block {} nor any block after it",
+                txid, from
+            ))
+        })?;
+    }
+}
+
+// The `getaddressinfo` fields used to tell the wallet's own outputs apart.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AddressInfo {
+    pub ismine: bool,
+    #[serde(default)]
+    pub iswatchonly: bool,
+    pub ischange: Option<bool>,
+}
+
+// A connection to the node or to one of its wallets, with a method for every
+// RPC the program makes, so the whole RPC surface is in this one place. RPCs
+// the typed `RpcApi` lacks go through the generic `call`; those whose result
+// only some caller cares about return whatever type it deserializes into.
+pub struct Node {
+    client: Client,
+}
+
+impl Node {
+    pub fn new(client: Client) -> Self {
+        Node { client }
+    }
+
+    // Everything the wallet holds, immature coinbase rewards included.
+    pub fn total_balance(&self) -> Result<Amount> {
+        let mine = self.balances()?.mine;
+        Ok(mine.trusted + mine.untrusted_pending + mine.immature)
+    }
+
+    // Height of the block `hash`.
+    pub fn block_height(&self, hash: &BlockHash) -> Result<u64> {
+        Ok(self.client.get_block_header_info(hash)?.height as u64)
+    }
+
+    // A fresh address of the wallet under `label`.
+    pub fn new_address(
+        &self,
+        label: &str,
+        address_type: Option<AddressType>,
+    ) -> Result<Address<NetworkUnchecked>> {
+        Ok(self.client.get_new_address(Some(label), address_type)?)
+    }
+
+    // Pay every recipient from a single transaction via the `send` RPC.
+    pub fn send_many(&self, recipients: &[Payment], options: &SendOptions) -> Result<SendOutcome> {
+        let outputs: Vec<serde_json::Value> = recipients
+            .iter()
+            .map(|p| json!({ p.address.to_string(): p.amount.to_btc() }))
+            .collect();
+        self.send_outputs(json!(outputs), options)
+    }
+
+    // Sweep every coin of the wallet to `addr` with the `sendall` RPC (Core
+    // v24.0 and later), paying the fee out of the swept amount.
+    pub fn send_all(&self, addr: &Address, options: &SendOptions) -> Result<SendOutcome> {
+        // sendall has no subtract_fee_from_outputs: it always does that
+        let mut sendall_options = options.json_options();
+        sendall_options.remove("subtract_fee_from_outputs");
+        let args = [
+            json!([addr.to_string()]), // recipients
+            json!(null),               // conf target
+            json!(null),               // estimate mode
+            json!(null),               // fee rate (set in the options instead)
+            json!(sendall_options),    // fee_rate / replaceable options
+        ];
+        send_outcome(self.client.call::<SendResult>("sendall", &args)?)
+    }
+
+    // Shared `send` RPC call; `outputs` is the JSON array of `{address: amount}` objects.
+    fn send_outputs(
+        &self,
+        outputs: serde_json::Value,
+        options: &SendOptions,
+    ) -> Result<SendOutcome> {
+        let args = [
+            outputs,                       // recipient addresses and amounts
+            json!(null),                   // conf target
+            json!(null),                   // estimate mode
+            json!(null),                   // fee rate in sats/vb (set in the options instead)
+            json!(options.json_options()), // fee_rate / replaceable options
+        ];
+
+        send_outcome(self.client.call::<SendResult>("send", &args)?)
+    }
+
+    // `getmempoolentry` for an unconfirmed `txid`, as returned by the node.
+    pub fn mempool_entry(&self, txid: &Txid) -> Result<serde_json::Value> {
+        Ok(self
+            .client
+            .call("getmempoolentry", &[json!(txid.to_string())])?)
+    }
+
+    // `getmempoolinfo`.
+    pub fn mempool_info(&self) -> Result<GetMempoolInfoResult> {
+        Ok(self.client.get_mempool_info()?)
+    }
+
+    // Verbose `getrawmempool`: every mempool entry by txid.
+    pub fn raw_mempool_verbose<T: DeserializeOwned>(&self) -> Result<HashMap<Txid, T>> {
+        Ok(self.client.call("getrawmempool", &[json!(true)])?)
+    }
+
+    // Verbose `getmempoolancestors` of `txid`, by txid.
+    pub fn mempool_ancestors<T: DeserializeOwned>(&self, txid: &Txid) -> Result<HashMap<Txid, T>> {
+        Ok(self.client.call(
+            "getmempoolancestors",
+            &[json!(txid.to_string()), json!(true)],
+        )?)
+    }
+
+    // Verbose `getmempooldescendants` of `txid`, by txid.
+    pub fn mempool_descendants<T: DeserializeOwned>(
+        &self,
+        txid: &Txid,
+    ) -> Result<HashMap<Txid, T>> {
+        Ok(self.client.call(
+            "getmempooldescendants",
+            &[json!(txid.to_string()), json!(true)],
+        )?)
+    }
+}
+
+// Chain and node
+impl Node {
+    pub fn blockchain_info(&self) -> Result<GetBlockchainInfoResult> {
+        Ok(self.client.get_blockchain_info()?)
+    }
+
+    pub fn network_info(&self) -> Result<GetNetworkInfoResult> {
+        Ok(self.client.get_network_info()?)
+    }
+
+    // The node's version number, e.g. 270000 for v27.0.0.
+    pub fn version(&self) -> Result<usize> {
+        Ok(self.client.version()?)
+    }
+
+    pub fn block_header(&self, hash: &BlockHash) -> Result<GetBlockHeaderResult> {
+        Ok(self.client.get_block_header_info(hash)?)
+    }
+
+    pub fn chain_tips(&self) -> Result<GetChainTipsResult> {
+        Ok(self.client.get_chain_tips()?)
+    }
+
+    // `getblockstats` for the block at `height`, limited to `stats`.
+    pub fn block_stats<T: DeserializeOwned>(&self, height: usize, stats: &[&str]) -> Result<T> {
+        Ok(self
+            .client
+            .call("getblockstats", &[json!(height), json!(stats)])?)
+    }
+
+    // A single `generatetoaddress` call; see mining::generate_blocks for the
+    // checked and retried version.
+    pub fn generate_to_address(&self, count: u64, addr: &Address) -> Result<Vec<BlockHash>> {
+        Ok(self.client.generate_to_address(count, addr)?)
+    }
+
+    pub fn estimate_smart_fee(&self, conf_target: u16) -> Result<EstimateSmartFeeResult> {
+        Ok(self.client.estimate_smart_fee(conf_target, None)?)
+    }
+}
+
+// Wallet management, on the node connection
+impl Node {
+    pub fn list_wallets(&self) -> Result<Vec<String>> {
+        Ok(self.client.list_wallets()?)
+    }
+
+    pub fn list_wallet_dir(&self) -> Result<Vec<String>> {
+        Ok(self.client.list_wallet_dir()?)
+    }
+
+    // `createwallet` through the generic `call`, because the typed
+    // `create_wallet` has no `descriptors` parameter. A `watch_only` wallet is
+    // created blank and without private keys; `descriptors: None` leaves the
+    // wallet type to the node's default.
+    pub fn create_wallet(
+        &self,
+        name: &str,
+        watch_only: bool,
+        descriptors: Option<bool>,
+    ) -> Result<()> {
+        let args = [
+            json!(name),
+            json!(watch_only),  // disable_private_keys
+            json!(watch_only),  // blank
+            json!(""),          // passphrase
+            json!(false),       // avoid_reuse
+            json!(descriptors), // descriptors (null: node default)
+        ];
+        self.client
+            .call::<serde_json::Value>("createwallet", &args)?;
+        Ok(())
+    }
+
+    pub fn load_wallet(&self, name: &str) -> Result<()> {
+        self.client.load_wallet(name)?;
+        Ok(())
+    }
+
+    pub fn unload_wallet(&self, name: &str) -> Result<()> {
+        self.client.unload_wallet(Some(name))?;
+        Ok(())
+    }
+}
+
+// The wallet behind the connection
+impl Node {
+    pub fn balances(&self) -> Result<GetBalancesResult> {
+        Ok(self.client.get_balances()?)
+    }
+
+    // `getwalletinfo`, as whatever subset of it the caller needs.
+    pub fn wallet_info<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(self.client.call("getwalletinfo", &[])?)
+    }
+
+    // `backupwallet` to `path`, which the node writes itself.
+    pub fn backup_wallet(&self, path: &Path) -> Result<()> {
+        self.client
+            .call::<serde_json::Value>("backupwallet", &[json!(path)])?;
+        Ok(())
+    }
+
+    // `rescanblockchain` from `from_height` to the tip.
+    pub fn rescan_blockchain<T: DeserializeOwned>(&self, from_height: u32) -> Result<T> {
+        Ok(self
+            .client
+            .call("rescanblockchain", &[json!(from_height)])?)
+    }
+
+    pub fn wallet_passphrase(&self, passphrase: &str, timeout_secs: u64) -> Result<()> {
+        self.client.call::<serde_json::Value>(
+            "walletpassphrase",
+            &[json!(passphrase), json!(timeout_secs)],
+        )?;
+        Ok(())
+    }
+
+    pub fn wallet_lock(&self) -> Result<()> {
+        self.client.call::<serde_json::Value>("walletlock", &[])?;
+        Ok(())
+    }
+
+    pub fn set_label(&self, addr: &str, label: &str) -> Result<()> {
+        self.client
+            .call::<serde_json::Value>("setlabel", &[json!(addr), json!(label)])?;
+        Ok(())
+    }
+
+    // `listlabels`.
+    pub fn labels(&self) -> Result<Vec<String>> {
+        Ok(self.client.call("listlabels", &[])?)
+    }
+
+    // `getaddressesbylabel`: the addresses under `label`, with their purpose.
+    pub fn addresses_by_label(
+        &self,
+        label: &str,
+    ) -> Result<HashMap<Address<NetworkUnchecked>, serde_json::Value>> {
+        Ok(self.client.call("getaddressesbylabel", &[json!(label)])?)
+    }
+
+    // `getdescriptorinfo` for `desc`.
+    pub fn descriptor_info<T: DeserializeOwned>(&self, desc: &str) -> Result<T> {
+        Ok(self.client.call("getdescriptorinfo", &[json!(desc)])?)
+    }
+
+    // `importdescriptors` with `requests`, one result per request.
+    pub fn import_descriptors<T: DeserializeOwned>(
+        &self,
+        requests: &[serde_json::Value],
+    ) -> Result<Vec<T>> {
+        Ok(self.client.call("importdescriptors", &[json!(requests)])?)
+    }
+
+    // `deriveaddresses`: the address at `index` of `desc` (a one-element range).
+    pub fn derive_addresses(
+        &self,
+        desc: &str,
+        index: u32,
+    ) -> Result<Vec<Address<NetworkUnchecked>>> {
+        Ok(self
+            .client
+            .call("deriveaddresses", &[json!(desc), json!([index, index])])?)
+    }
+
+    // `listunspent`, optionally with at least `min_conf` confirmations.
+    pub fn list_unspent(&self, min_conf: Option<usize>) -> Result<Vec<ListUnspentResultEntry>> {
+        Ok(self.client.list_unspent(min_conf, None, None, None, None)?)
+    }
+}
+
+// Transactions
+impl Node {
+    pub fn raw_transaction_hex(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<String> {
+        Ok(self.client.get_raw_transaction_hex(txid, block_hash)?)
+    }
+
+    // `decoderawtransaction` of `hex`, as the typed result or as plain JSON.
+    pub fn decode_raw_transaction<T: DeserializeOwned>(&self, hex: &str) -> Result<T> {
+        Ok(self.client.call("decoderawtransaction", &[json!(hex)])?)
+    }
+
+    // `createrawtransaction` through the generic `call`, because the typed one
+    // cannot express a `data` output. Returns the unsigned transaction hex.
+    pub fn create_raw_transaction(
+        &self,
+        inputs: &[serde_json::Value],
+        outputs: &[serde_json::Value],
+        locktime: u32,
+        replaceable: bool,
+    ) -> Result<String> {
+        Ok(self.client.call(
+            "createrawtransaction",
+            &[
+                json!(inputs),
+                json!(outputs),
+                json!(locktime),
+                json!(replaceable),
+            ],
+        )?)
+    }
+
+    pub fn fund_raw_transaction(
+        &self,
+        hex: &str,
+        options: &SendOptions,
+    ) -> Result<FundRawTransactionResult> {
+        Ok(self.client.call(
+            "fundrawtransaction",
+            &[json!(hex), json!(options.json_options())],
+        )?)
+    }
+
+    pub fn sign_raw_transaction_with_wallet<R: RawTx>(
+        &self,
+        tx: R,
+    ) -> Result<SignRawTransactionResult> {
+        Ok(self
+            .client
+            .sign_raw_transaction_with_wallet(tx, None, None)?)
+    }
+
+    pub fn send_raw_transaction<R: RawTx>(&self, tx: R) -> Result<Txid> {
+        Ok(self.client.send_raw_transaction(tx)?)
+    }
+
+    // `walletcreatefundedpsbt` paying `outputs`, with inputs chosen by the wallet.
+    pub fn wallet_create_funded_psbt(
+        &self,
+        outputs: &[serde_json::Value],
+        options: &SendOptions,
+    ) -> Result<WalletCreateFundedPsbtResult> {
+        Ok(self.client.call(
+            "walletcreatefundedpsbt",
+            &[
+                json!([]),
+                json!(outputs),
+                json!(0),
+                json!(options.json_options()),
+            ],
+        )?)
+    }
+
+    // `walletprocesspsbt`, signing what the wallet can.
+    pub fn wallet_process_psbt(&self, psbt: &str) -> Result<WalletProcessPsbtResult> {
+        Ok(self
+            .client
+            .wallet_process_psbt(psbt, Some(true), None, None)?)
+    }
+
+    // `finalizepsbt`, extracting the raw transaction when complete.
+    pub fn finalize_psbt(&self, psbt: &str) -> Result<FinalizePsbtResult> {
+        Ok(self.client.finalize_psbt(psbt, Some(true))?)
+    }
+
+    // `bumpfee` for `txid` at `fee_rate` sat/vB; the replacement's txid.
+    pub fn bump_fee(&self, txid: &Txid, fee_rate: f64) -> Result<Txid> {
+        #[derive(Deserialize)]
+        struct BumpFeeResult {
+            txid: Txid,
+        }
+        let result: BumpFeeResult = self.client.call(
+            "bumpfee",
+            &[json!(txid.to_string()), json!({ "fee_rate": fee_rate })],
+        )?;
+        Ok(result.txid)
+    }
+}
+
+impl NodeApi for Node {
+    // See mining::generate_blocks.
+    fn mine(&self, count: u64, addr: &Address) -> Result<Vec<BlockHash>> {
+        mining::generate_blocks(self, count, addr)
+    }
+
+    fn balance(&self) -> Result<Amount> {
+        Ok(self.client.get_balance(None, None)?)
+    }
+
+    // Send `amount` to `addr`. With a fee rate (sat/vB) we use the `send` RPC,
+    // because the typed `send_to_address` has no `fee_rate` parameter; without
+    // one Core estimates the fee itself.
+    fn send(&self, addr: &Address, amount: Amount, options: &SendOptions) -> Result<SendOutcome> {
+        match options.fee_rate {
+            Some(_) => self.send_outputs(json!([{ addr.to_string(): amount.to_btc() }]), options),
+            None => Ok(SendOutcome::Broadcast(self.client.send_to_address(
+                addr,                       // recipient address
+                amount,                     // amount
+                None,                       // comment
+                None,                       // comment_to
+                Some(options.subtract_fee), // subtract_fee_from_amount
+                Some(options.replaceable),  // replaceable
+                None,                       // conf_target
+                None,                       // estimate_mode
+            )?)),
+        }
+    }
+
+    fn tx_info(&self, txid: &Txid) -> Result<GetTransactionResult> {
+        Ok(self.client.get_transaction(txid, None)?)
+    }
+
+    fn block_info(&self, hash: &BlockHash) -> Result<GetBlockResult> {
+        Ok(self.client.get_block_info(hash)?)
+    }
+
+    fn block_count(&self) -> Result<u64> {
+        Ok(self.client.get_block_count()?)
+    }
+
+    fn raw_transaction_info(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<GetRawTransactionResult> {
+        Ok(self.client.get_raw_transaction_info(txid, block_hash)?)
+    }
+
+    // A single JSON-RPC batch of `getrawtransaction` calls rather than one
+    // round trip each.
+    fn raw_transaction_hexes(&self, txids: &[Txid]) -> Result<Vec<String>> {
+        if txids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let client = self.client.get_jsonrpc_client();
+        let params = txids
+            .iter()
+            .map(|txid| Ok([to_raw_value(&txid.to_string())?]))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let requests: Vec<_> = params
+            .iter()
+            .map(|p| client.build_request("getrawtransaction", p))
+            .collect();
+        let responses = client
+            .send_batch(&requests)
+            .map_err(bitcoincore_rpc::Error::from)?;
+        txids
+            .iter()
+            .zip(responses)
+            .map(|(txid, response)| {
+                let response = response.ok_or_else(|| {
+                    AppError::InvalidState(format!("no batch response for {}", txid))
+                })?;
+                Ok(response.result().map_err(bitcoincore_rpc::Error::from)?)
+            })
+            .collect()
+    }
+
+    fn address_info(&self, addr: &str) -> Result<AddressInfo> {
+        Ok(self.client.call("getaddressinfo", &[json!(addr)])?)
+    }
+}
+
+// What a send produced: a broadcast transaction, or a PSBT the wallet could not
+// fully sign (e.g. a watch-only or external-signer wallet), which the caller
+// has to get signed and broadcast itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendOutcome {
+    Broadcast(Txid),
+    // The base64 PSBT returned by `send`.
+    NeedsSigning(String),
+}
+
+// The `send` RPC result: a txid when `complete`, otherwise the partly signed PSBT.
+#[derive(Deserialize)]
+struct SendResult {
+    complete: bool,
+    txid: Option<String>,
+    psbt: Option<String>,
+}
+
+fn send_outcome(result: SendResult) -> Result<SendOutcome> {
+    match result {
+        SendResult {
+            complete: true,
+            txid: Some(txid),
+            ..
+        } => Ok(SendOutcome::Broadcast(parse_txid(&txid)?)),
+        SendResult {
+            complete: false,
+            psbt: Some(psbt),
+            ..
+        } => Ok(SendOutcome::NeedsSigning(psbt)),
+        SendResult { complete, .. } => Err(AppError::InvalidState(format!(
+            "send returned complete={} without a {}",
+            complete,
+            if complete { "txid" } else { "PSBT" }
+        ))),
+    }
+}
+
+// Parse a txid string handed back by the node.
+fn parse_txid(txid: &str) -> Result<Txid> {
+    txid.parse::<Txid>()
+        .map_err(|e| AppError::InvalidState(format!("node returned a bad txid: {}", e)))
+}
+
+// A `NodeApi` that answers from canned responses, for the tests of every module
+// built on it.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+    use bitcoincore_rpc::bitcoin::{Network, Transaction};
+
+    // A node that answers from canned responses instead of RPC.
+    #[derive(Default)]
+    pub struct MockNode {
+        pub balance: Amount,
+        pub block_count: u64,
+        // `gettransaction` results as the node would return them
+        pub transactions: HashMap<Txid, serde_json::Value>,
+        // Verbose `getrawtransaction` results, likewise (see `raw_tx`)
+        pub raw_transactions: HashMap<Txid, serde_json::Value>,
+        // `getblock` results, likewise (see `block`)
+        pub blocks: HashMap<BlockHash, serde_json::Value>,
+        pub addresses: HashMap<String, AddressInfo>,
+    }
+
+    impl NodeApi for MockNode {
+        fn mine(&self, count: u64, _addr: &Address) -> Result<Vec<BlockHash>> {
+            Ok((0..count)
+                .map(|i| BlockHash::from_byte_array([i as u8; 32]))
+                .collect())
+        }
+
+        fn balance(&self) -> Result<Amount> {
+            Ok(self.balance)
+        }
+
+        fn send(
+            &self,
+            _addr: &Address,
+            _amount: Amount,
+            _options: &SendOptions,
+        ) -> Result<SendOutcome> {
+            Ok(SendOutcome::Broadcast(Txid::from_byte_array([0xaa; 32])))
+        }
+
+        fn tx_info(&self, txid: &Txid) -> Result<GetTransactionResult> {
+            let canned = self
+                .transactions
+                .get(txid)
+                .ok_or_else(|| AppError::InvalidState(format!("no canned transaction {}", txid)))?;
+            Ok(serde_json::from_value(canned.clone())?)
+        }
+
+        fn block_info(&self, hash: &BlockHash) -> Result<GetBlockResult> {
+            let canned = self
+                .blocks
+                .get(hash)
+                .ok_or_else(|| AppError::InvalidState(format!("no canned block {}", hash)))?;
+            Ok(serde_json::from_value(canned.clone())?)
+        }
+
+        fn block_count(&self) -> Result<u64> {
+            Ok(self.block_count)
+        }
+
+        fn raw_transaction_info(
+            &self,
+            txid: &Txid,
+            _block_hash: Option<&BlockHash>,
+        ) -> Result<GetRawTransactionResult> {
+            let canned = self.raw_transactions.get(txid).ok_or_else(|| {
+                AppError::InvalidState(format!("no canned raw transaction {}", txid))
+            })?;
+            Ok(serde_json::from_value(canned.clone())?)
+        }
+
+        fn raw_transaction_hexes(&self, txids: &[Txid]) -> Result<Vec<String>> {
+            txids
+                .iter()
+                .map(|txid| {
+                    Ok(self
+                        .raw_transaction_info(txid, None)?
+                        .hex
+                        .to_lower_hex_string())
+                })
+                .collect()
+        }
+
+        fn address_info(&self, addr: &str) -> Result<AddressInfo> {
+            Ok(self.addresses.get(addr).copied().unwrap_or(AddressInfo {
+                ismine: false,
+                iswatchonly: false,
+                ischange: None,
+            }))
+        }
+    }
+
+    // A minimal `gettransaction` result, with `fee` only for the wallet's own sends.
+    pub fn wallet_tx(txid: Txid, amount: f64, fee: Option<f64>) -> serde_json::Value {
+        let mut tx = json!({
+            "amount": amount,
+            "confirmations": 1,
+            "txid": txid.to_string(),
+            "walletconflicts": [],
+            "time": 1_700_000_000,
+            "timereceived": 1_700_000_000,
+            "bip125-replaceable": "no",
+            "details": [],
+            "hex": "00",
+        });
+        if let Some(fee) = fee {
+            tx["fee"] = json!(fee);
+        }
+        tx
+    }
+
+    // A minimal `getblock` result at `height` holding `txids`, followed by `next`.
+    pub fn block(height: usize, txids: &[Txid], next: Option<BlockHash>) -> serde_json::Value {
+        let mut block = json!({
+            "hash": BlockHash::from_byte_array([height as u8; 32]).to_string(),
+            "confirmations": 1,
+            "size": 250,
+            "weight": 1000,
+            "height": height,
+            "version": 0x2000_0000,
+            "merkleroot": "00".repeat(32),
+            "tx": txids.iter().map(Txid::to_string).collect::<Vec<_>>(),
+            "time": 1_700_000_000,
+            "nonce": 0,
+            "bits": "207fffff",
+            "difficulty": 4.6e-10,
+            "chainwork": "00",
+            "nTx": txids.len(),
+        });
+        if let Some(next) = next {
+            block["nextblockhash"] = json!(next.to_string());
+        }
+        block
+    }
+
+    // A verbose `getrawtransaction` result for `tx`, confirmed in `blockhash`
+    // if given. Inputs spending the null outpoint are reported as coinbase.
+    pub fn raw_tx(tx: &Transaction, blockhash: Option<BlockHash>) -> serde_json::Value {
+        let vin: Vec<_> = tx
+            .input
+            .iter()
+            .map(|input| {
+                if input.previous_output.is_null() {
+                    json!({ "coinbase": "00", "sequence": input.sequence.0 })
+                } else {
+                    json!({
+                        "txid": input.previous_output.txid.to_string(),
+                        "vout": input.previous_output.vout,
+                        "scriptSig": { "asm": "", "hex": "" },
+                        "sequence": input.sequence.0,
+                    })
+                }
+            })
+            .collect();
+        let vout: Vec<_> = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(n, output)| {
+                let mut script_pub_key = json!({
+                    "asm": "",
+                    "hex": output.script_pubkey.to_hex_string(),
+                });
+                if let Ok(address) = Address::from_script(&output.script_pubkey, Network::Regtest) {
+                    script_pub_key["address"] = json!(address.to_string());
+                }
+                json!({
+                    "value": output.value.to_btc(),
+                    "n": n,
+                    "scriptPubKey": script_pub_key,
+                })
+            })
+            .collect();
+        let mut raw = json!({
+            "hex": serialize_hex(tx),
+            "txid": tx.txid().to_string(),
+            "hash": tx.wtxid().to_string(),
+            "size": tx.total_size(),
+            "vsize": tx.vsize(),
+            "version": tx.version.0,
+            "locktime": tx.lock_time.to_consensus_u32(),
+            "vin": vin,
+            "vout": vout,
+        });
+        if let Some(hash) = blockhash {
+            raw["blockhash"] = json!(hash.to_string());
+        }
+        raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{block, wallet_tx, MockNode};
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    #[test]
+    fn containing_block_finds_a_transaction_that_landed_a_block_later() {
+        let ours = Txid::from_byte_array([1; 32]);
+        let coinbases = [
+            Txid::from_byte_array([0xc1; 32]),
+            Txid::from_byte_array([0xc2; 32]),
+        ];
+        let hashes = [
+            BlockHash::from_byte_array([101; 32]),
+            BlockHash::from_byte_array([102; 32]),
+        ];
+        let mut node = MockNode::default();
+        // Block 101 was mined elsewhere without our transaction; it is in 102
+        node.blocks
+            .insert(hashes[0], block(101, &coinbases[..1], Some(hashes[1])));
+        node.blocks
+            .insert(hashes[1], block(102, &[coinbases[1], ours], None));
+
+        assert_eq!(
+            containing_block(&node, &ours, hashes[0]).unwrap(),
+            hashes[1]
+        );
+        assert_eq!(
+            containing_block(&node, &ours, hashes[1]).unwrap(),
+            hashes[1]
+        );
+        // Not in the chain from block 101 up to the tip
+        assert!(matches!(
+            containing_block(&node, &Txid::from_byte_array([9; 32]), hashes[0]),
+            Err(AppError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn incomplete_send_hands_back_the_psbt_instead_of_panicking() {
+        let result: SendResult =
+            serde_json::from_value(json!({ "complete": false, "psbt": "cHNidP8BAH0CAAAAAQ==" }))
+                .unwrap();
+        assert_eq!(
+            send_outcome(result).unwrap(),
+            SendOutcome::NeedsSigning("cHNidP8BAH0CAAAAAQ==".to_owned())
+        );
+
+        let txid = Txid::from_byte_array([5; 32]);
+        let result: SendResult =
+            serde_json::from_value(json!({ "complete": true, "txid": txid.to_string() })).unwrap();
+        assert_eq!(send_outcome(result).unwrap(), SendOutcome::Broadcast(txid));
+
+        let result: SendResult = serde_json::from_value(json!({ "complete": false })).unwrap();
+        assert!(matches!(
+            send_outcome(result),
+            Err(AppError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn fees_paid_sums_the_wallet_fees_of_its_own_sends() {
+        let sent = [
+            Txid::from_byte_array([1; 32]),
+            Txid::from_byte_array([2; 32]),
+        ];
+        let received = Txid::from_byte_array([3; 32]);
+        let mut node = MockNode::default();
+        node.transactions
+            .insert(sent[0], wallet_tx(sent[0], -20.0, Some(-0.0000141)));
+        node.transactions
+            .insert(sent[1], wallet_tx(sent[1], -5.0, Some(-0.0000209)));
+        node.transactions
+            .insert(received, wallet_tx(received, 1.0, None));
+
+        assert_eq!(
+            node.tx_fee(&sent[0]).unwrap(),
+            Some(Amount::from_sat(1_410))
+        );
+        assert_eq!(
+            fees_paid(&node, &[sent[0], sent[1], received]).unwrap(),
+            Amount::from_sat(3_500)
+        );
+        // An unknown transaction is an error, not a zero fee
+        assert!(fees_paid(&node, &[Txid::from_byte_array([9; 32])]).is_err());
+    }
+}
@@ -0,0 +1,623 @@
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::AppError;
+use crate::tx::{BlockStats, BlockTimes, MempoolSummary, TxDetails};
+
+// Set once from --quiet at startup; read by the `say!` macro.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// Unit used to render amounts for humans and in out.txt.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unit {
+    #[default]
+    Btc,
+    #[value(name = "mbtc")]
+    MBtc,
+    Sat,
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unit::Btc => write!(f, "BTC"),
+            Unit::MBtc => write!(f, "mBTC"),
+            Unit::Sat => write!(f, "sat"),
+        }
+    }
+}
+
+// Render `a` as a bare number in `unit`, to exactly satoshi precision: BTC
+// with 8 decimal places and mBTC with 5 (e.g. `20.00000000`, `29.99999859`).
+// Built from the integer satoshi count, so no f64 rounding shows up.
+pub fn format_amount(a: Amount, unit: Unit) -> String {
+    let sats = a.to_sat();
+    match unit {
+        Unit::Btc => format!("{}.{:08}", sats / 100_000_000, sats % 100_000_000),
+        Unit::MBtc => format!("{}.{:05}", sats / 100_000, sats % 100_000),
+        Unit::Sat => sats.to_string(),
+    }
+}
+
+// Everything we report about the Miner -> Trader transaction. Amounts serialize
+// as BTC floats so the JSON matches the values written to the legacy out.txt.
+// The block fields are `None` for a transaction that has not been confirmed.
+#[derive(Serialize, Debug, Clone)]
+pub struct TxReport {
+    pub txid: String,
+    pub miner_input_address: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub miner_input_amount: Amount,
+    pub trader_output_address: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub trader_output_amount: Amount,
+    pub miner_change_address: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub miner_change_amount: Amount,
+    // Every payment output (Trader first), including extra `--recipient`s.
+    pub recipients: Vec<RecipientReport>,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub transaction_fees: Amount,
+    // JSON only: out.txt keeps its ten-line format. `None` when the size is unknown.
+    pub fee_rate_sat_vb: Option<f64>,
+    pub vsize: Option<usize>,
+    pub weight: Option<usize>,
+    pub size: Option<usize>,
+    // The fee rate we asked Core to use, from --fee-rate or estimatesmartfee.
+    pub chosen_fee_rate_sat_vb: Option<f64>,
+    // Unconfirmed ancestors/descendants seen in the mempool right after the
+    // send; `None` for a dry run.
+    pub mempool_ancestor_count: Option<usize>,
+    pub mempool_descendant_count: Option<usize>,
+    // getmempoolinfo and our fee rate ranking at that moment; `None` for a dry run.
+    pub mempool: Option<MempoolSummary>,
+    pub block_height: Option<usize>,
+    pub block_hash: Option<String>,
+    // getblockstats of the confirming block, when the node could provide them.
+    pub block_stats: Option<BlockStats>,
+    // Header time and median time past of the confirming block (JSON only).
+    pub block_times: Option<BlockTimes>,
+    // Confirmations the Miner input (usually a mature coinbase) had when spent.
+    pub input_confirmations: Option<u64>,
+    // True for a `--dry-run` report of a transaction that was never broadcast.
+    pub simulated: bool,
+    // True for a `--sweep`, which spends everything and so has no change output.
+    pub sweep: bool,
+    // True for `--fee-from-trader`: the fee came out of the Trader output, which
+    // is then below `requested_amount`, rather than out of the Miner's change.
+    pub fee_from_trader: bool,
+    // The amount asked to be sent to the Trader (--amount, or the swept balance).
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc::opt")]
+    pub requested_amount: Option<Amount>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecipientReport {
+    pub address: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub amount: Amount,
+}
+
+impl From<&TxDetails> for TxReport {
+    fn from(details: &TxDetails) -> Self {
+        TxReport {
+            txid: details.txid.to_string(),
+            miner_input_address: details.miner_input_address.clone(),
+            miner_input_amount: details.miner_input_amount,
+            trader_output_address: details.trader_output_address.clone(),
+            trader_output_amount: details.trader_output_amount,
+            miner_change_address: details.miner_change_address.clone(),
+            miner_change_amount: details.miner_change_amount,
+            recipients: details
+                .recipient_outputs
+                .iter()
+                .map(|o| RecipientReport {
+                    address: o.address.clone(),
+                    amount: o.value,
+                })
+                .collect(),
+            transaction_fees: details.fee,
+            fee_rate_sat_vb: details.fee_rate_sat_vb,
+            vsize: details.vsize,
+            weight: details.weight,
+            size: details.size,
+            chosen_fee_rate_sat_vb: None,
+            mempool_ancestor_count: None,
+            mempool_descendant_count: None,
+            mempool: None,
+            block_height: details.block_height,
+            block_hash: details.block_hash.map(|hash| hash.to_string()),
+            block_stats: None,
+            block_times: None,
+            input_confirmations: details.input_confirmations,
+            simulated: details.simulated,
+            sweep: false,
+            fee_from_trader: false,
+            requested_amount: None,
+        }
+    }
+}
+
+// Format a unix timestamp (seconds) as an RFC 3339 UTC time, e.g.
+// 2011-02-02T23:16:42Z. Timestamps chrono cannot represent are written as-is.
+pub fn rfc3339(unix_secs: u64) -> String {
+    i64::try_from(unix_secs)
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map_or_else(
+            || unix_secs.to_string(),
+            |time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        )
+}
+
+// How far the Miner balance is off from what the run should have left it with,
+// in satoshis (positive: more than expected):
+//
+//     final - (start + created - sent - fees + fees_collected)
+//
+// `created` is the subsidy of the blocks mined, `sent` what left the wallet to
+// other wallets and `fees` what the send paid; `fees_collected` is the part of
+// those fees that came back through the coinbase of a block the Miner mined.
+// Both balances must include immature coinbase rewards for this to be zero.
+pub fn reconcile(
+    start_balance: Amount,
+    created: Amount,
+    sent: Amount,
+    fees: Amount,
+    fees_collected: Amount,
+    final_balance: Amount,
+) -> i64 {
+    let sats = |a: Amount| a.to_sat() as i64;
+    sats(final_balance)
+        - (sats(start_balance) + sats(created) - sats(sent) - sats(fees) + sats(fees_collected))
+}
+
+// Layout of the report written to the out path (--format).
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    // The canonical ten positional lines of out.txt.
+    #[default]
+    Text,
+    // The full report as pretty-printed JSON, like out.json.
+    Json,
+    // The ten out.txt values as `key=value` lines.
+    Kv,
+}
+
+// The ten out.txt values in order, with the key each has in the `kv` format.
+// Amounts are in `unit`; an unconfirmed transaction has block height `-1` and
+// block hash `unconfirmed`, and a sweep without change has change address
+// `none` and amount 0.
+fn report_fields(report: &TxReport, unit: Unit) -> [(&'static str, String); 10] {
+    let change_address = if report.miner_change_address.is_empty() {
+        "none".to_owned()
+    } else {
+        report.miner_change_address.clone()
+    };
+    [
+        ("txid", report.txid.clone()),
+        ("miner_input_address", report.miner_input_address.clone()),
+        (
+            "miner_input_amount",
+            format_amount(report.miner_input_amount, unit),
+        ),
+        (
+            "trader_output_address",
+            report.trader_output_address.clone(),
+        ),
+        (
+            "trader_output_amount",
+            format_amount(report.trader_output_amount, unit),
+        ),
+        ("miner_change_address", change_address),
+        (
+            "miner_change_amount",
+            format_amount(report.miner_change_amount, unit),
+        ),
+        ("fee", format_amount(report.transaction_fees, unit)),
+        (
+            "block_height",
+            report
+                .block_height
+                .map_or_else(|| "-1".to_owned(), |height| height.to_string()),
+        ),
+        (
+            "block_hash",
+            report
+                .block_hash
+                .clone()
+                .unwrap_or_else(|| "unconfirmed".to_owned()),
+        ),
+    ]
+}
+
+// Render `report` in `format`, with the text and kv amounts in `unit`.
+pub fn render_report(report: &TxReport, format: Format, unit: Unit) -> String {
+    match format {
+        Format::Text => report_fields(report, unit)
+            .iter()
+            .map(|(_, value)| format!("{}\n", value))
+            .collect(),
+        Format::Kv => report_fields(report, unit)
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect(),
+        // Plain data with string keys, so serializing cannot fail
+        Format::Json => serde_json::to_string_pretty(report).expect("TxReport serializes") + "\n",
+    }
+}
+
+// The txid recorded in an existing report, whatever its `Format`: the first
+// line of a text report, its `txid=` line in kv, or the `txid` field of a JSON
+// report. `None` if there is no parsable txid.
+pub fn report_txid(contents: &str) -> Option<Txid> {
+    let txid = if contents.trim_start().starts_with('{') {
+        let report: serde_json::Value = serde_json::from_str(contents).ok()?;
+        report["txid"].as_str()?.to_owned()
+    } else {
+        let first_line = contents.lines().next()?.trim();
+        first_line
+            .strip_prefix("txid=")
+            .unwrap_or(first_line)
+            .to_owned()
+    };
+    txid.parse().ok()
+}
+
+// Write `report` to `path` in the canonical ten-line out.txt order the graders
+// expect, with amounts in `unit`, creating parent directories as needed (see
+// `write_report_as`).
+#[cfg(test)]
+pub fn write_report(path: &Path, report: &TxReport, unit: Unit) -> crate::error::Result<()> {
+    write_report_as(path, report, Format::Text, unit)
+}
+
+// Write `report` to `path` in `format` (see `render_report`), creating parent
+// directories as needed. Refuses to write a report whose trader or change
+// output was never identified rather than emit empty lines; that is an
+// AppError::InvalidState, while a failed write is AppError::Io.
+pub fn write_report_as(
+    path: &Path,
+    report: &TxReport,
+    format: Format,
+    unit: Unit,
+) -> crate::error::Result<()> {
+    if report.trader_output_address.is_empty() {
+        return Err(AppError::InvalidState(
+            "trader output was not found in the transaction".to_owned(),
+        ));
+    }
+    if report.miner_change_address.is_empty() && !report.sweep {
+        return Err(AppError::InvalidState(
+            "miner change output was not found in the transaction".to_owned(),
+        ));
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(fs::write(path, render_report(report, format, unit))?)
+}
+
+// Read the out.txt at `path` back and check that its ten lines match `expected`
+// as written in `unit`, every line exactly. Amounts are formatted from whole
+// satoshis, so they read back unchanged too. Catches a truncated file or lines
+// written out of order. A mismatch is an AppError::InvalidState.
+pub fn verify_report(path: &Path, expected: &TxReport, unit: Unit) -> crate::error::Result<()> {
+    let mismatch = |line: usize, what: &str, found: &str, wanted: &str| {
+        AppError::InvalidState(format!(
+            "{} line {} ({}) reads {:?}, expected {:?}",
+            path.display(),
+            line,
+            what,
+            found,
+            wanted
+        ))
+    };
+
+    let contents = fs::read_to_string(path)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    if lines.len() != 10 {
+        return Err(AppError::InvalidState(format!(
+            "{} has {} lines, expected 10",
+            path.display(),
+            lines.len()
+        )));
+    }
+
+    let change_address = if expected.miner_change_address.is_empty() {
+        "none"
+    } else {
+        &expected.miner_change_address
+    };
+    let height = expected
+        .block_height
+        .map_or_else(|| "-1".to_owned(), |h| h.to_string());
+    let exact = [
+        (0, "txid", expected.txid.as_str()),
+        (1, "miner input address", &expected.miner_input_address),
+        (3, "trader output address", &expected.trader_output_address),
+        (5, "miner change address", change_address),
+        (8, "block height", &height),
+        (
+            9,
+            "block hash",
+            expected.block_hash.as_deref().unwrap_or("unconfirmed"),
+        ),
+    ];
+    for (i, what, wanted) in exact {
+        if lines[i] != wanted {
+            return Err(mismatch(i + 1, what, lines[i], wanted));
+        }
+    }
+
+    let amounts = [
+        (2, "miner input amount", expected.miner_input_amount),
+        (4, "trader output amount", expected.trader_output_amount),
+        (6, "miner change amount", expected.miner_change_amount),
+        (7, "transaction fee", expected.transaction_fees),
+    ];
+    for (i, what, amount) in amounts {
+        let wanted = format_amount(amount, unit);
+        if lines[i] != wanted {
+            return Err(mismatch(i + 1, what, lines[i], &wanted));
+        }
+    }
+    Ok(())
+}
+
+// Append one line for this run to the history at `path`, creating the file (and
+// its parent directories) on first use:
+//
+//     <RFC 3339 timestamp> txid=<txid> fee=<BTC> miner_balance=<BTC>
+pub fn append_run_log(
+    path: &Path,
+    txid: &str,
+    fee: Amount,
+    miner_balance: Amount,
+) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "{} txid={} fee={} miner_balance={}",
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        txid,
+        format_amount(fee, Unit::Btc),
+        format_amount(miner_balance, Unit::Btc)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use std::path::PathBuf;
+
+    // A fresh directory for one test's files, removed again when dropped (so
+    // also when the test fails). `name`, the test's own, keeps tests that run
+    // in parallel in the same process apart.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("rust-capstone-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Details of a transaction still in the mempool, as extract_tx_details
+    // builds them without a block hash.
+    fn unconfirmed_details() -> TxDetails {
+        TxDetails {
+            txid: Txid::from_byte_array([7; 32]),
+            miner_input_address: "bcrt1qminer".to_owned(),
+            miner_input_amount: Amount::from_int_btc(50),
+            trader_output_address: "bcrt1qtrader".to_owned(),
+            trader_output_amount: Amount::from_int_btc(20),
+            miner_change_address: "bcrt1qchange".to_owned(),
+            miner_change_amount: Amount::from_sat(2_999_998_590),
+            recipient_outputs: Vec::new(),
+            fee: Amount::from_sat(1_410),
+            vsize: Some(141),
+            weight: Some(561),
+            size: Some(222),
+            fee_rate_sat_vb: Some(10.0),
+            block_height: None,
+            block_hash: None,
+            input_confirmations: Some(101),
+            simulated: false,
+        }
+    }
+
+    #[test]
+    fn kv_format_writes_the_out_txt_values_as_key_value_lines() {
+        let report = TxReport::from(&unconfirmed_details());
+
+        let kv = render_report(&report, Format::Kv, Unit::Sat);
+        let text = render_report(&report, Format::Text, Unit::Sat);
+
+        let lines: Vec<&str> = kv.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], format!("txid={}", report.txid));
+        assert_eq!(
+            lines[7],
+            format!("fee={}", report.transaction_fees.to_sat())
+        );
+        assert_eq!(lines[8], "block_height=-1");
+        assert_eq!(lines[9], "block_hash=unconfirmed");
+        // Same values, in the same order, as the positional format
+        let values: Vec<&str> = kv.lines().map(|l| l.split_once('=').unwrap().1).collect();
+        assert_eq!(values, text.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_txid_is_read_back_from_every_report_format() {
+        let report = TxReport::from(&unconfirmed_details());
+        let txid = Txid::from_byte_array([7; 32]);
+        for format in [Format::Text, Format::Kv, Format::Json] {
+            let rendered = render_report(&report, format, Unit::Btc);
+            assert_eq!(report_txid(&rendered), Some(txid), "{:?}", format);
+        }
+        assert_eq!(report_txid(""), None);
+        assert_eq!(report_txid("{\"fee\": 1}"), None);
+        assert_eq!(report_txid("not a txid\n"), None);
+    }
+
+    #[test]
+    fn amounts_render_at_satoshi_precision_without_float_artifacts() {
+        // 0.1 + 0.2 is 0.30000000000000004 as f64
+        let sum = Amount::from_btc(0.1).unwrap() + Amount::from_btc(0.2).unwrap();
+        assert_eq!(format_amount(sum, Unit::Btc), "0.30000000");
+        assert_eq!(
+            format_amount(Amount::from_sat(4_999_990_000), Unit::Btc),
+            "49.99990000"
+        );
+        assert_eq!(
+            format_amount(Amount::from_sat(1_410), Unit::Btc),
+            "0.00001410"
+        );
+        assert_eq!(
+            format_amount(Amount::from_sat(1_410), Unit::MBtc),
+            "0.01410"
+        );
+        assert_eq!(format_amount(Amount::from_sat(1_410), Unit::Sat), "1410");
+    }
+
+    #[test]
+    fn block_times_are_formatted_as_rfc3339_utc() {
+        // Timestamp of the regtest genesis block
+        assert_eq!(rfc3339(1_296_688_602), "2011-02-02T23:16:42Z");
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn reconciliation_balances_when_the_fee_is_mined_back() {
+        let btc = Amount::from_int_btc;
+        let fee = Amount::from_sat(1_410);
+        // 102 blocks at 50 BTC, 20 BTC sent, fee collected by our own block
+        assert_eq!(
+            reconcile(btc(0), btc(5_100), btc(20), fee, fee, btc(5_080)),
+            0
+        );
+        // Fee paid to someone else's block: it is gone from the balance
+        assert_eq!(
+            reconcile(
+                btc(0),
+                btc(5_100),
+                btc(20),
+                fee,
+                Amount::ZERO,
+                btc(5_080) - fee
+            ),
+            0
+        );
+        // A missing satoshi shows up as a negative discrepancy
+        assert_eq!(
+            reconcile(
+                btc(10),
+                btc(50),
+                btc(20),
+                fee,
+                fee,
+                btc(40) - Amount::ONE_SAT
+            ),
+            -1
+        );
+    }
+
+    #[test]
+    fn sweep_report_is_written_without_a_change_output() {
+        let mut details = unconfirmed_details();
+        details.miner_change_address = String::new();
+        details.miner_change_amount = Amount::ZERO;
+        let mut report = TxReport::from(&details);
+
+        let dir = TempDir::new("sweep_report_is_written_without_a_change_output");
+        let path = dir.path("out.txt");
+        // Without the sweep marker a missing change output is an error
+        assert!(write_report(&path, &report, Unit::Btc).is_err());
+
+        report.sweep = true;
+        write_report(&path, &report, Unit::Btc).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[5], "none");
+        assert_eq!(lines[6], "0.00000000");
+    }
+
+    #[test]
+    fn written_report_round_trips_and_tampering_is_caught() {
+        let report = TxReport::from(&unconfirmed_details());
+        let dir = TempDir::new("written_report_round_trips_and_tampering_is_caught");
+        let path = dir.path("out.txt");
+
+        for unit in [Unit::Btc, Unit::MBtc, Unit::Sat] {
+            write_report(&path, &report, unit).unwrap();
+            verify_report(&path, &report, unit).unwrap();
+        }
+
+        // Swap the trader and change lines, as an ordering bug would
+        let contents = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<&str> = contents.lines().collect();
+        lines.swap(3, 5);
+        fs::write(&path, lines.join("\n")).unwrap();
+        let swapped = verify_report(&path, &report, Unit::Sat);
+
+        // And drop the last line, as a truncated write would
+        fs::write(&path, lines[..9].join("\n")).unwrap();
+        let truncated = verify_report(&path, &report, Unit::Sat);
+
+        assert!(matches!(swapped, Err(AppError::InvalidState(_))));
+        assert!(matches!(truncated, Err(AppError::InvalidState(_))));
+    }
+
+    #[test]
+    fn unconfirmed_transaction_is_reported_without_a_block() {
+        let report = TxReport::from(&unconfirmed_details());
+        assert_eq!(report.block_height, None);
+        assert_eq!(report.block_hash, None);
+        assert!(!report.simulated);
+
+        let dir = TempDir::new("unconfirmed_transaction_is_reported_without_a_block");
+        let path = dir.path("out.txt");
+        write_report(&path, &report, Unit::Btc).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[7], "0.00001410");
+        assert_eq!(lines[8], "-1");
+        assert_eq!(lines[9], "unconfirmed");
+    }
+}
@@ -0,0 +1,54 @@
+use bitcoincore_rpc::jsonrpc;
+use log::warn;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{AppError, Result};
+
+// Core's RPC_IN_WARMUP: the node is still loading blocks or wallets.
+const RPC_IN_WARMUP: i32 = -28;
+// Core's RPC_WALLET_ERROR: generic wallet failure, transient only while the
+// wallet is busy rescanning.
+const RPC_WALLET_ERROR: i32 = -4;
+
+// Whether retrying the failed call can reasonably succeed. Treated as transient:
+//
+// * -28 (RPC_IN_WARMUP), the node is still starting up;
+// * -4 (RPC_WALLET_ERROR) when the wallet reports it is currently rescanning.
+//
+// Everything else is permanent, in particular -6 (RPC_WALLET_INSUFFICIENT_FUNDS)
+// and other -4 wallet errors. Transport errors are not retried either: the node
+// may have accepted a send whose response was lost, and retrying would pay twice.
+pub fn is_transient(e: &bitcoincore_rpc::Error) -> bool {
+    match e {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error)) => {
+            rpc_error.code == RPC_IN_WARMUP
+                || (rpc_error.code == RPC_WALLET_ERROR
+                    && rpc_error.message.contains("currently rescanning"))
+        }
+        _ => false,
+    }
+}
+
+// Run `f` up to `attempts` times, sleeping `delay` between tries, as long as it
+// fails with a transient RPC error. Any other error is returned straight away.
+pub fn retry_transient<T>(
+    attempts: u32,
+    delay: Duration,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(AppError::Rpc(e)) if attempt < attempts && is_transient(&e) => {
+                warn!(
+                    "Transient RPC error (attempt {}/{}): {}; retrying in {:?}",
+                    attempt, attempts, e, delay
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,1202 @@
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::consensus::encode::deserialize;
+use bitcoincore_rpc::bitcoin::hex::{DisplayHex, FromHex};
+use bitcoincore_rpc::bitcoin::{
+    Address, Amount, BlockHash, Network, OutPoint, Transaction, TxOut, Txid,
+};
+use bitcoincore_rpc::json::{
+    DecodeRawTransactionResult, FundRawTransactionResult, GetRawTransactionResultVin,
+    GetRawTransactionResultVout,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::error::{AppError, Result};
+use crate::mining;
+use crate::node::{Node, NodeApi};
+use crate::report;
+
+// Details of the Miner -> Trader transaction: everything out.txt needs, kept in
+// typed form (amounts in `Amount`, so fee maths stays in satoshis). The block
+// fields are `None` while the transaction is not confirmed; `simulated` marks a
+// transaction that was only built and never broadcast (`--dry-run`).
+#[derive(Debug, Clone)]
+pub struct TxDetails {
+    pub txid: Txid,
+    pub miner_input_address: String,
+    pub miner_input_amount: Amount,
+    pub trader_output_address: String,
+    pub trader_output_amount: Amount,
+    pub miner_change_address: String,
+    pub miner_change_amount: Amount,
+    // Every requested payment output, Trader first, in the order requested.
+    pub recipient_outputs: Vec<OutputInfo>,
+    pub fee: Amount,
+    // Virtual size in vbytes and the resulting fee rate, when known.
+    pub vsize: Option<usize>,
+    // Weight in weight units and serialized size in bytes (witness included).
+    pub weight: Option<usize>,
+    pub size: Option<usize>,
+    pub fee_rate_sat_vb: Option<f64>,
+    pub block_height: Option<usize>,
+    pub block_hash: Option<BlockHash>,
+    // Confirmations the Miner input had when spent (see
+    // mining::confirmations_when_spent), counting the mempool transaction and
+    // a dry run as spent in the next block.
+    pub input_confirmations: Option<u64>,
+    pub simulated: bool,
+}
+
+// A payment the transaction is expected to make. The Trader payment comes first,
+// followed by any extra `--recipient` payments.
+#[derive(Debug, Clone)]
+pub struct Payment {
+    pub address: Address,
+    pub amount: Amount,
+}
+
+// What a payment of `requested` that also bears `fee` (SendOptions::subtract_fee)
+// actually delivers. An error if the fee would eat the whole payment.
+pub fn amount_after_fee(requested: Amount, fee: Amount) -> Result<Amount> {
+    requested.checked_sub(fee).ok_or_else(|| {
+        AppError::InvalidState(format!(
+            "fee of {} BTC exceeds the {} BTC payment it is taken from",
+            fee.to_btc(),
+            requested.to_btc()
+        ))
+    })
+}
+
+// Check that an address reported by the node (or given on the command line) is
+// valid for `network`, the one place that turns a mismatch into an
+// `AppError::AddressValidation`.
+pub fn checked_address(addr: Address<NetworkUnchecked>, network: Network) -> Result<Address> {
+    addr.require_network(network)
+        .map_err(|e| AppError::AddressValidation(e.to_string()))
+}
+
+// Options shared by every send path.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendOptions {
+    // Explicit fee rate in sat/vB; Core estimates the fee when `None`.
+    pub fee_rate: Option<f64>,
+    // Signal BIP 125 replaceability so the fee can later be bumped.
+    pub replaceable: bool,
+    // Take the fee out of the first payment (the Trader's) instead of adding
+    // it on top, as `--sweep` and `--fee-from-trader` do.
+    pub subtract_fee: bool,
+}
+
+impl SendOptions {
+    // The options object understood by `send`, `fundrawtransaction` and
+    // `walletcreatefundedpsbt`. Built by hand because the typed option structs
+    // serialize `fee_rate` as BTC while Core reads it as sat/vB.
+    pub fn json_options(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut options = serde_json::Map::new();
+        if let Some(rate) = self.fee_rate {
+            options.insert("fee_rate".to_owned(), json!(rate));
+        }
+        options.insert("replaceable".to_owned(), json!(self.replaceable));
+        if self.subtract_fee {
+            options.insert("subtract_fee_from_outputs".to_owned(), json!([0]));
+        }
+        options
+    }
+}
+
+// One address-bearing output, reduced to what classification needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputInfo {
+    pub address: String,
+    pub value: Amount,
+    // Whether the sending (Miner) wallet owns this address.
+    pub miner_owned: bool,
+    // `getaddressinfo.ischange` from the Miner wallet: true for an address of
+    // its internal (change) descriptor. `None` when the node does not say.
+    pub is_change: Option<bool>,
+}
+
+// Match each expected `(address, amount)` payment to an output, then pick the
+// Miner's change output. A payment output must match both address and amount and
+// each output is claimed at most once; change is an unclaimed output the Miner
+// wallet owns, preferably one it flags `ischange` (on descriptor wallets, an
+// address of the internal descriptor). Neither step relies on "whatever is left
+// over", so colliding amounts, extra outputs or a recipient that is itself a
+// Miner address can't be mistaken for change.
+pub fn classify_outputs<'a>(
+    outputs: &'a [OutputInfo],
+    payments: &[(String, Amount)],
+) -> (Vec<Option<&'a OutputInfo>>, Option<&'a OutputInfo>) {
+    let mut claimed = vec![false; outputs.len()];
+    let mut matched = Vec::with_capacity(payments.len());
+    for (address, amount) in payments {
+        let idx = outputs
+            .iter()
+            .enumerate()
+            .position(|(i, o)| !claimed[i] && o.address == *address && o.value == *amount);
+        if let Some(i) = idx {
+            claimed[i] = true;
+        }
+        matched.push(idx.map(|i| &outputs[i]));
+    }
+    let unclaimed_mine = || {
+        outputs
+            .iter()
+            .enumerate()
+            .filter(|(i, o)| !claimed[*i] && o.miner_owned)
+            .map(|(_, o)| o)
+    };
+    let change = unclaimed_mine()
+        .find(|o| o.is_change == Some(true))
+        .or_else(|| unclaimed_mine().next());
+    (matched, change)
+}
+
+// Guard against change that went to the Trader's own address, which would make
+// the report show one script as both payment and change. If `change` has the
+// address of the Trader output (the first of `matched`), fall back to another
+// unclaimed Miner-owned output at a different address, or to no change at all.
+// Logs the resolution taken.
+pub fn change_distinct_from_trader<'a>(
+    outputs: &'a [OutputInfo],
+    matched: &[Option<&'a OutputInfo>],
+    change: Option<&'a OutputInfo>,
+) -> Option<&'a OutputInfo> {
+    let (Some(trader), Some(change)) = (matched.first().copied().flatten(), change) else {
+        return change;
+    };
+    if trader.address != change.address {
+        return Some(change);
+    }
+    warn!(
+        "Change output pays the Trader address {} as well; re-classifying by Miner ownership",
+        trader.address
+    );
+    let claimed = |o: &OutputInfo| matched.iter().flatten().any(|m| std::ptr::eq(*m, o));
+    let resolved = outputs
+        .iter()
+        .find(|o| o.miner_owned && o.address != trader.address && !claimed(o));
+    match resolved {
+        Some(o) => info!("Using the Miner output {} as change instead", o.address),
+        None => warn!("No other Miner-owned output; the transaction is reported without change"),
+    }
+    resolved
+}
+
+// Ask the wallet behind `rpc` whether it owns `addr` (`getaddressinfo.ismine`),
+// counting addresses a legacy wallet only watches (`iswatchonly`) as well.
+pub fn wallet_owns<N: NodeApi + ?Sized>(rpc: &N, addr: &str) -> Result<bool> {
+    Ok(address_ownership(rpc, addr)?.0)
+}
+
+// `wallet_owns`, together with `getaddressinfo.ischange` when the node reports it.
+pub fn address_ownership<N: NodeApi + ?Sized>(rpc: &N, addr: &str) -> Result<(bool, Option<bool>)> {
+    let info = rpc.address_info(addr)?;
+    Ok((info.ismine || info.iswatchonly, info.ischange))
+}
+
+// The previous outputs spent by the inputs `vin` of `txid`, in input order. A
+// coinbase transaction spends nothing, so it has no Miner input to report and
+// is rejected with a descriptive error instead of a panic.
+fn spent_outpoints(txid: &Txid, vin: &[GetRawTransactionResultVin]) -> Result<Vec<OutPoint>> {
+    if vin.iter().any(|input| input.is_coinbase()) {
+        return Err(AppError::InvalidState(format!(
+            "{} is a coinbase transaction and spends no previous output",
+            txid
+        )));
+    }
+    let outpoints = vin
+        .iter()
+        .map(|input| match (input.txid, input.vout) {
+            (Some(txid), Some(vout)) => Ok(OutPoint { txid, vout }),
+            _ => Err(AppError::InvalidState(format!(
+                "an input of {} has no previous output",
+                txid
+            ))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if outpoints.is_empty() {
+        return Err(AppError::InvalidState(format!("{} has no inputs", txid)));
+    }
+    Ok(outpoints)
+}
+
+// The outputs spent by `inputs`, in the same order, looked up with a single
+// JSON-RPC batch of `getrawtransaction` calls rather than one round trip per
+// input. On a transaction with many inputs (e.g. a wallet that selected dozens
+// of small coinbase UTXOs) the lookup then costs one network latency instead
+// of N. The previous transactions are usually coinbases, which is fine: only
+// their outputs are looked at.
+pub fn batch_prevouts<N: NodeApi + ?Sized>(rpc: &N, inputs: &[OutPoint]) -> Result<Vec<TxOut>> {
+    let txids: Vec<Txid> = inputs.iter().map(|o| o.txid).collect();
+    let hexes = rpc.raw_transaction_hexes(&txids)?;
+
+    inputs
+        .iter()
+        .zip(hexes)
+        .map(|(outpoint, hex)| {
+            let prev_tx: Transaction = Vec::<u8>::from_hex(&hex)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| deserialize(&bytes).map_err(|e| e.to_string()))
+                .map_err(|e| {
+                    AppError::InvalidState(format!(
+                        "bad previous transaction {}: {}",
+                        outpoint.txid, e
+                    ))
+                })?;
+            prev_tx
+                .output
+                .into_iter()
+                .nth(outpoint.vout as usize)
+                .ok_or_else(|| {
+                    AppError::InvalidState(format!(
+                        "previous transaction has no output {}",
+                        outpoint
+                    ))
+                })
+        })
+        .collect()
+}
+
+// Fee of a transaction: the sum of all `inputs` minus the sum of all `outputs`,
+// or `None` if the outputs would exceed the inputs.
+pub fn transaction_fee(inputs: &[Amount], outputs: &[Amount]) -> Option<Amount> {
+    let total_in: Amount = inputs.iter().copied().sum();
+    let total_out: Amount = outputs.iter().copied().sum();
+    total_in.checked_sub(total_out)
+}
+
+// Look up `txid` in `block_hash` and work out its input, payment outputs, change
+// output and fee. `rpc` must be the Miner wallet so change can be identified by
+// ownership. Addresses are validated against `network`. With no `block_hash`
+// the transaction is looked up in the mempool and the details carry no block.
+pub fn extract_tx_details<N: NodeApi + ?Sized>(
+    rpc: &N,
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+    payments: &[Payment],
+    network: Network,
+) -> Result<TxDetails> {
+    let raw_tx_info = rpc.raw_transaction_info(txid, block_hash)?;
+    let block_height = block_hash
+        .map(|hash| rpc.block_info(hash).map(|block| block.height))
+        .transpose()?;
+    let spending_height = match block_height {
+        Some(height) => height as u64,
+        None => rpc.block_count()? + 1,
+    };
+
+    let mut details = build_details(
+        rpc,
+        *txid,
+        &raw_tx_info.vin,
+        &raw_tx_info.vout,
+        payments,
+        network,
+        spending_height,
+    )?;
+    let sizes = tx_sizes(
+        &raw_tx_info
+            .transaction()
+            .map_err(bitcoincore_rpc::Error::from)?,
+    );
+    details.vsize = Some(sizes.vsize);
+    details.weight = Some(sizes.weight);
+    details.size = Some(sizes.size);
+    details.fee_rate_sat_vb = fee_rate_sat_vb(details.fee, sizes.vsize);
+    details.block_height = block_height;
+    details.block_hash = block_hash.copied();
+    Ok(details)
+}
+
+// Size metrics of a transaction. `size` counts witness bytes in full, while
+// `weight` discounts them (4 units per base byte, 1 per witness byte) and
+// `vsize` is the weight / 4, rounded up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxSizes {
+    pub size: usize,
+    pub vsize: usize,
+    pub weight: usize,
+}
+
+// Compute the size metrics from the decoded transaction. `getrawtransaction`
+// reports size and vsize but not weight, so all three come from here.
+pub fn tx_sizes(tx: &Transaction) -> TxSizes {
+    TxSizes {
+        size: tx.total_size(),
+        vsize: tx.vsize(),
+        weight: tx.weight().to_wu() as usize,
+    }
+}
+
+// Fee rate in sat/vB, or `None` when the size is unknown (zero).
+pub fn fee_rate_sat_vb(fee: Amount, vsize: usize) -> Option<f64> {
+    if vsize == 0 {
+        None
+    } else {
+        Some(fee.to_sat() as f64 / vsize as f64)
+    }
+}
+
+// Shape of a transaction built through `createrawtransaction` rather than
+// letting the wallet pick everything: pinned inputs and an optional data output.
+#[derive(Debug, Clone, Default)]
+pub struct RawTxTemplate {
+    // Inputs spent first; the wallet only adds more if they do not cover the
+    // payments and fee.
+    pub inputs: Vec<OutPoint>,
+    // Hex payload of an extra `OP_RETURN` output (at most 80 bytes).
+    pub op_return: Option<String>,
+    // Block-height nLockTime; the inputs get a non-final sequence so it is
+    // enforced.
+    pub locktime: Option<u32>,
+}
+
+impl RawTxTemplate {
+    // Whether anything requires the raw-transaction send path.
+    pub fn is_custom(&self) -> bool {
+        !self.inputs.is_empty() || self.op_return.is_some() || self.locktime.is_some()
+    }
+}
+
+// Have the Miner wallet build (but not sign or broadcast) a transaction making
+// all `payments` shaped by `template`, with the given send options.
+pub fn fund_transaction(
+    rpc: &Node,
+    payments: &[Payment],
+    template: &RawTxTemplate,
+    options: &SendOptions,
+) -> Result<FundRawTransactionResult> {
+    let inputs: Vec<serde_json::Value> = template
+        .inputs
+        .iter()
+        .map(|o| json!({ "txid": o.txid.to_string(), "vout": o.vout }))
+        .collect();
+    let mut outputs: Vec<serde_json::Value> = payments
+        .iter()
+        .map(|p| json!({ p.address.to_string(): p.amount.to_btc() }))
+        .collect();
+    if let Some(data) = &template.op_return {
+        outputs.push(json!({ "data": data }));
+    }
+    let raw_hex = rpc.create_raw_transaction(
+        &inputs,
+        &outputs,
+        template.locktime.unwrap_or(0),
+        options.replaceable,
+    )?;
+    rpc.fund_raw_transaction(&raw_hex, options)
+}
+
+// Make all `payments` through the PSBT workflow: `walletcreatefundedpsbt` builds
+// and funds it, `walletprocesspsbt` signs it, `finalizepsbt` extracts the raw
+// transaction and `sendrawtransaction` broadcasts it.
+pub fn send_with_psbt(rpc: &Node, payments: &[Payment], options: &SendOptions) -> Result<Txid> {
+    let outputs: Vec<serde_json::Value> = payments
+        .iter()
+        .map(|p| json!({ p.address.to_string(): p.amount.to_btc() }))
+        .collect();
+    let created = rpc.wallet_create_funded_psbt(&outputs, options)?;
+    info!("Created funded PSBT (fee {} BTC)", created.fee.to_btc());
+    sign_and_broadcast_psbt(rpc, &created.psbt)
+}
+
+// Sign the base64 `psbt` with the wallet behind `rpc`, finalize it and
+// broadcast the resulting transaction. Fails if the wallet cannot sign every
+// input.
+pub fn sign_and_broadcast_psbt(rpc: &Node, psbt: &str) -> Result<Txid> {
+    let processed = rpc.wallet_process_psbt(psbt)?;
+    if !processed.complete {
+        return Err(AppError::InvalidState(
+            "the Miner wallet could not fully sign the PSBT".to_owned(),
+        ));
+    }
+    info!("Signed PSBT with the Miner wallet");
+
+    let finalized = rpc.finalize_psbt(&processed.psbt)?;
+    let raw_tx = match finalized.hex {
+        Some(hex) if finalized.complete => hex,
+        _ => {
+            return Err(AppError::InvalidState(
+                "finalizepsbt did not produce a complete transaction".to_owned(),
+            ))
+        }
+    };
+    info!("Finalized PSBT into a raw transaction");
+
+    rpc.send_raw_transaction(&raw_tx[..])
+}
+
+// Check that `utxo` is an unspent output the Miner wallet can spend, so coin
+// control fails with a clear message instead of an opaque signing error.
+pub fn ensure_spendable_utxo(rpc: &Node, utxo: &OutPoint) -> Result<()> {
+    let unspent = rpc.list_unspent(None)?;
+    match unspent
+        .iter()
+        .find(|u| u.txid == utxo.txid && u.vout == utxo.vout)
+    {
+        Some(u) if u.spendable => Ok(()),
+        Some(_) => Err(AppError::InvalidArgument(format!(
+            "UTXO {} is not spendable yet (immature coinbase or watch-only)",
+            utxo
+        ))),
+        None => Err(AppError::InvalidArgument(format!(
+            "UTXO {} is not an unspent output of the Miner wallet",
+            utxo
+        ))),
+    }
+}
+
+// Fund the transaction built from `template` and sign it with the Miner
+// wallet, returning the raw transaction ready for broadcast.
+pub fn sign_raw(
+    rpc: &Node,
+    payments: &[Payment],
+    template: &RawTxTemplate,
+    options: &SendOptions,
+) -> Result<Vec<u8>> {
+    let funded = fund_transaction(rpc, payments, template, options)?;
+    info!("Funded raw transaction (fee {} BTC)", funded.fee.to_btc());
+
+    let signed = rpc.sign_raw_transaction_with_wallet(&funded.hex)?;
+    if !signed.complete {
+        return Err(AppError::InvalidState(
+            "the Miner wallet could not fully sign the transaction".to_owned(),
+        ));
+    }
+    Ok(signed.hex)
+}
+
+// Make all `payments` through the raw-transaction workflow: fund and sign the
+// transaction built from `template`, then broadcast it.
+pub fn send_raw(
+    rpc: &Node,
+    payments: &[Payment],
+    template: &RawTxTemplate,
+    options: &SendOptions,
+) -> Result<Txid> {
+    let raw_tx = sign_raw(rpc, payments, template, options)?;
+    rpc.send_raw_transaction(&raw_tx)
+}
+
+// Broadcast a transaction time-locked to block height `locktime`. It can only
+// enter the mempool once the tip is at `locktime` (the next block, the first
+// that may include it, is then above the lock), so we first show that an early
+// broadcast is rejected as non-final, then mine to `locktime` and broadcast
+// again. The confirmation loop afterwards mines the block that includes it.
+pub fn broadcast_after_locktime(
+    rpc: &Node,
+    raw_tx: &[u8],
+    locktime: u32,
+    mining_addr: &Address,
+) -> Result<Txid> {
+    let height = rpc.block_count()?;
+    if height >= locktime as u64 {
+        info!(
+            "Chain is already at height {}, past locktime {}; broadcasting directly",
+            height, locktime
+        );
+        return rpc.send_raw_transaction(raw_tx);
+    }
+
+    match rpc.send_raw_transaction(raw_tx) {
+        Err(e) => info!(
+            "Broadcast at height {} rejected before locktime {}, as expected: {}",
+            height, locktime, e
+        ),
+        Ok(txid) => {
+            return Err(AppError::InvalidState(format!(
+                "time-locked transaction {} was accepted at height {}, before locktime {}",
+                txid, height, locktime
+            )))
+        }
+    }
+
+    mining::mine_until_height(rpc, mining_addr, locktime as u64)?;
+    rpc.send_raw_transaction(raw_tx)
+}
+
+// In-mempool ancestors and descendants of an unconfirmed transaction (not
+// counting the transaction itself), with their combined base fees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MempoolRelatives {
+    pub ancestor_count: usize,
+    pub ancestor_fees: Amount,
+    pub descendant_count: usize,
+    pub descendant_fees: Amount,
+}
+
+// Look up the mempool ancestors and descendants of `txid` with the verbose forms
+// of `getmempoolancestors` / `getmempooldescendants`. A transaction with no
+// unconfirmed relatives simply gets counts of zero.
+pub fn mempool_relatives(rpc: &Node, txid: &Txid) -> Result<MempoolRelatives> {
+    #[derive(Deserialize)]
+    struct Fees {
+        #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+        base: Amount,
+    }
+    #[derive(Deserialize)]
+    struct Entry {
+        fees: Fees,
+    }
+
+    let summarise = |entries: HashMap<Txid, Entry>| -> (usize, Amount) {
+        (entries.len(), entries.values().map(|e| e.fees.base).sum())
+    };
+    let (ancestor_count, ancestor_fees) = summarise(rpc.mempool_ancestors(txid)?);
+    let (descendant_count, descendant_fees) = summarise(rpc.mempool_descendants(txid)?);
+    Ok(MempoolRelatives {
+        ancestor_count,
+        ancestor_fees,
+        descendant_count,
+        descendant_fees,
+    })
+}
+
+// The node's mempool as seen right after the send, and where our transaction
+// ranks in it by fee rate (sat/vB). On an idle regtest node ours is usually the
+// only entry, so both counts are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MempoolSummary {
+    pub size: usize,
+    pub bytes: usize,
+    pub min_relay_fee_sat_vb: f64,
+    pub mempool_min_fee_sat_vb: f64,
+    pub fee_rate_sat_vb: f64,
+    // Other mempool transactions paying a lower or higher fee rate than ours.
+    pub lower_fee_rate_count: usize,
+    pub higher_fee_rate_count: usize,
+}
+
+// Count the fee rates in `others` below and above `ours`; equal rates count
+// as neither.
+pub fn fee_rate_rank(ours: f64, others: &[f64]) -> (usize, usize) {
+    let lower = others.iter().filter(|&&rate| rate < ours).count();
+    let higher = others.iter().filter(|&&rate| rate > ours).count();
+    (lower, higher)
+}
+
+// Summarise the mempool with `getmempoolinfo` and rank the unconfirmed `txid`
+// against every other entry of the verbose `getrawmempool`.
+pub fn mempool_summary(rpc: &Node, txid: &Txid) -> Result<MempoolSummary> {
+    #[derive(Deserialize)]
+    struct Fees {
+        #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+        base: Amount,
+    }
+    #[derive(Deserialize)]
+    struct Entry {
+        vsize: u64,
+        fees: Fees,
+    }
+
+    let info = rpc.mempool_info()?;
+    let entries: HashMap<Txid, Entry> = rpc.raw_mempool_verbose()?;
+    let rate = |e: &Entry| e.fees.base.to_sat() as f64 / e.vsize.max(1) as f64;
+    let ours = entries
+        .get(txid)
+        .map(rate)
+        .ok_or_else(|| AppError::InvalidState(format!("{} is not in the mempool", txid)))?;
+    let others: Vec<f64> = entries
+        .iter()
+        .filter(|(id, _)| *id != txid)
+        .map(|(_, e)| rate(e))
+        .collect();
+    let (lower_fee_rate_count, higher_fee_rate_count) = fee_rate_rank(ours, &others);
+    // Both minimum fees are reported per kvB
+    let per_vb = |per_kvb: Amount| per_kvb.to_sat() as f64 / 1000.0;
+    Ok(MempoolSummary {
+        size: info.size,
+        bytes: info.bytes,
+        min_relay_fee_sat_vb: per_vb(info.min_relay_tx_fee),
+        mempool_min_fee_sat_vb: per_vb(info.mempool_min_fee),
+        fee_rate_sat_vb: ours,
+        lower_fee_rate_count,
+        higher_fee_rate_count,
+    })
+}
+
+// A few `getblockstats` figures for the block that confirmed the send.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BlockStats {
+    // Number of transactions, coinbase included.
+    pub txs: usize,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub total_fee: Amount,
+    // 50th percentile fee rate of the block's transactions, in sat/vB.
+    pub median_fee_rate_sat_vb: u64,
+}
+
+// Fetch `getblockstats` for the block at `height`. Returns `None`, with a
+// warning, when the node cannot provide them (for example a pruned node that no
+// longer has the block's undo data), since the stats are only context.
+pub fn block_stats(rpc: &Node, height: usize) -> Result<Option<BlockStats>> {
+    #[derive(Deserialize)]
+    struct Stats {
+        txs: usize,
+        totalfee: u64,
+        // 10th, 25th, 50th, 75th and 90th percentiles
+        feerate_percentiles: [u64; 5],
+    }
+
+    match rpc.block_stats::<Stats>(height, &["txs", "totalfee", "feerate_percentiles"]) {
+        Ok(stats) => Ok(Some(BlockStats {
+            txs: stats.txs,
+            total_fee: Amount::from_sat(stats.totalfee),
+            median_fee_rate_sat_vb: stats.feerate_percentiles[2],
+        })),
+        Err(e) => {
+            warn!(
+                "getblockstats is unavailable for block {}; leaving block stats out of the report: {}",
+                height, e
+            );
+            Ok(None)
+        }
+    }
+}
+
+// When the block that confirmed the send was mined: its header `time` and the
+// `mediantime` of the eleven blocks ending with it (what timelocks compare
+// against), each as a unix timestamp and as RFC 3339.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockTimes {
+    pub time: u64,
+    pub time_rfc3339: String,
+    pub median_time: u64,
+    pub median_time_rfc3339: String,
+}
+
+// Read the header times of block `hash` with `getblockheader`.
+pub fn block_times(rpc: &Node, hash: &BlockHash) -> Result<BlockTimes> {
+    let header = rpc.block_header(hash)?;
+    let time = header.time as u64;
+    // Only missing from very old nodes; fall back to the block's own time
+    let median_time = header.median_time.map_or(time, |t| t as u64);
+    Ok(BlockTimes {
+        time,
+        time_rfc3339: report::rfc3339(time),
+        median_time,
+        median_time_rfc3339: report::rfc3339(median_time),
+    })
+}
+
+// Core's RPC_WALLET_ERROR and RPC_INVALID_PARAMETER, which `bumpfee` returns for
+// a transaction it cannot replace (not replaceable, already mined, no change
+// to cut from, ...). The wording differs across versions, so only the code is
+// matched.
+const RPC_WALLET_ERROR: i32 = -4;
+const RPC_INVALID_PARAMETER: i32 = -8;
+
+// Replace the unconfirmed `txid` with a higher-fee version at `fee_rate` sat/vB
+// via `bumpfee`, returning the replacement's txid. Fails with a clear error,
+// carrying the node's reason, when the wallet cannot replace it.
+pub fn bump_fee(rpc: &Node, txid: &Txid, fee_rate: f64) -> Result<Txid> {
+    match rpc.bump_fee(txid, fee_rate) {
+        Err(AppError::Rpc(bitcoincore_rpc::Error::JsonRpc(
+            bitcoincore_rpc::jsonrpc::Error::Rpc(e),
+        ))) if e.code == RPC_WALLET_ERROR || e.code == RPC_INVALID_PARAMETER => Err(
+            AppError::InvalidState(format!("cannot bump the fee of {}: {}", txid, e.message)),
+        ),
+        result => result,
+    }
+}
+
+// Work out the details of a funded but unbroadcast transaction, as `--dry-run`
+// reports it. The txid is final already since it does not commit to witnesses,
+// but the size (and so the fee rate) is not known until the transaction is signed.
+pub fn simulate_tx_details(
+    rpc: &Node,
+    funded: &FundRawTransactionResult,
+    payments: &[Payment],
+    network: Network,
+) -> Result<TxDetails> {
+    let decoded: DecodeRawTransactionResult =
+        rpc.decode_raw_transaction(&funded.hex.to_lower_hex_string())?;
+    let mut details = build_details(
+        rpc,
+        decoded.txid,
+        &decoded.vin,
+        &decoded.vout,
+        payments,
+        network,
+        rpc.block_count()? + 1,
+    )?;
+    details.simulated = true;
+    Ok(details)
+}
+
+// Shared input/output/fee analysis for confirmed and simulated transactions,
+// spent in block `spending_height`. The returned details have no block
+// information.
+fn build_details<N: NodeApi + ?Sized>(
+    rpc: &N,
+    txid: Txid,
+    vin: &[GetRawTransactionResultVin],
+    vout: &[GetRawTransactionResultVout],
+    payments: &[Payment],
+    network: Network,
+    spending_height: u64,
+) -> Result<TxDetails> {
+    // Look up every spent output; the first one is reported as the Miner input
+    let outpoints = spent_outpoints(&txid, vin)?;
+    let spent = batch_prevouts(rpc, &outpoints)?;
+    let input_confirmations = input_confirmations(rpc, &outpoints[0], spending_height)?;
+    let input_output = &spent[0];
+    let miner_input_address = Address::from_script(&input_output.script_pubkey, network)
+        .map_err(|e| AppError::AddressValidation(e.to_string()))?
+        .to_string();
+
+    // Collect the address-bearing outputs along with Miner ownership; an
+    // OP_RETURN data output has no address and is never trader or change
+    let mut outputs = Vec::new();
+    for output in vout {
+        if let Some(ref address) = output.script_pub_key.address {
+            let addr_str = checked_address(address.clone(), network)?.to_string();
+            let (miner_owned, is_change) = address_ownership(rpc, &addr_str)?;
+            outputs.push(OutputInfo {
+                address: addr_str,
+                value: output.value,
+                miner_owned,
+                is_change,
+            });
+        }
+    }
+
+    // Identify the payments (Trader first) and the change back to the Miner
+    let expected: Vec<(String, Amount)> = payments
+        .iter()
+        .map(|p| (p.address.to_string(), p.amount))
+        .collect();
+    let (matched, change) = classify_outputs(&outputs, &expected);
+    let change = change_distinct_from_trader(&outputs, &matched, change);
+    let (trader_output_address, trader_output_amount) = matched
+        .first()
+        .copied()
+        .flatten()
+        .map(|o| (o.address.clone(), o.value))
+        .unwrap_or_default();
+    let (miner_change_address, miner_change_amount) = change
+        .map(|o| (o.address.clone(), o.value))
+        .unwrap_or_default();
+    let recipient_outputs = matched.into_iter().flatten().cloned().collect();
+
+    // Calculate transaction fees using satoshis for precision: whatever the
+    // inputs hold that no output pays out
+    let input_values: Vec<Amount> = spent.iter().map(|o| o.value).collect();
+    let output_values: Vec<Amount> = vout.iter().map(|o| o.value).collect();
+    let fee = transaction_fee(&input_values, &output_values)
+        .ok_or_else(|| AppError::InvalidState(format!("outputs of {} exceed its inputs", txid)))?;
+
+    Ok(TxDetails {
+        txid,
+        miner_input_address,
+        miner_input_amount: input_output.value,
+        trader_output_address,
+        trader_output_amount,
+        miner_change_address,
+        miner_change_amount,
+        recipient_outputs,
+        fee,
+        vsize: None,
+        weight: None,
+        size: None,
+        fee_rate_sat_vb: None,
+        block_height: None,
+        block_hash: None,
+        input_confirmations: Some(input_confirmations),
+        simulated: false,
+    })
+}
+
+// Confirmations `outpoint` had when spent in block `spending_height`, from the
+// height of the block that created it (0 if that is still unconfirmed). Warns
+// when a coinbase output is spent before it matured.
+fn input_confirmations<N: NodeApi + ?Sized>(
+    rpc: &N,
+    outpoint: &OutPoint,
+    spending_height: u64,
+) -> Result<u64> {
+    let prev = rpc.raw_transaction_info(&outpoint.txid, None)?;
+    let Some(hash) = prev.blockhash else {
+        return Ok(0);
+    };
+    let created_height = rpc.block_info(&hash)?.height as u64;
+    let confirmations = mining::confirmations_when_spent(created_height, spending_height);
+    if prev.is_coinbase() && confirmations < mining::COINBASE_MATURITY {
+        warn!(
+            "Coinbase input {} was spent with only {} confirmations",
+            outpoint, confirmations
+        );
+    }
+    Ok(confirmations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(address: &str, btc: u64, miner_owned: bool) -> OutputInfo {
+        OutputInfo {
+            address: address.to_owned(),
+            value: Amount::from_int_btc(btc),
+            miner_owned,
+            is_change: None,
+        }
+    }
+
+    fn payment(address: &str, btc: u64) -> (String, Amount) {
+        (address.to_owned(), Amount::from_int_btc(btc))
+    }
+
+    fn input(prevout: Option<(Txid, u32)>, coinbase: bool) -> GetRawTransactionResultVin {
+        GetRawTransactionResultVin {
+            sequence: u32::MAX,
+            coinbase: coinbase.then(|| vec![0x51]),
+            txid: prevout.map(|(txid, _)| txid),
+            vout: prevout.map(|(_, vout)| vout),
+            script_sig: None,
+            txinwitness: None,
+        }
+    }
+
+    fn txid(byte: u8) -> Txid {
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+        Txid::from_byte_array([byte; 32])
+    }
+
+    #[test]
+    fn sweep_without_change_finds_the_trader_output_only() {
+        // A sweep pays everything, minus the fee, to the Trader: one output
+        let outputs = vec![OutputInfo {
+            address: "bcrt1qtrader".to_owned(),
+            value: Amount::from_sat(4_999_998_590),
+            miner_owned: false,
+            is_change: None,
+        }];
+        let (matched, change) =
+            classify_outputs(&outputs, &[("bcrt1qtrader".to_owned(), outputs[0].value)]);
+
+        assert_eq!(matched, vec![Some(&outputs[0])]);
+        assert_eq!(change, None);
+    }
+
+    #[test]
+    fn fee_from_trader_output_plus_fee_is_the_requested_amount() {
+        let requested = Amount::from_btc(20.0).unwrap();
+        let fee = Amount::from_sat(1_410);
+
+        let trader_output = amount_after_fee(requested, fee).unwrap();
+
+        assert!(trader_output < requested);
+        assert_eq!(trader_output + fee, requested);
+        assert!(matches!(
+            amount_after_fee(fee, requested),
+            Err(AppError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn descriptor_wallet_change_flag_wins_over_other_miner_outputs() {
+        // The Trader is paid, plus an extra output to a Miner receive address
+        // whose amount no payment asks for; Core put change on a bech32m
+        // address of the internal descriptor, which reports `ischange`
+        let receive = OutputInfo {
+            is_change: Some(false),
+            ..output("bcrt1qminerreceive", 3, true)
+        };
+        let change = OutputInfo {
+            is_change: Some(true),
+            ..output(
+                "bcrt1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqc8gma6",
+                29,
+                true,
+            )
+        };
+        let outputs = vec![output("bcrt1qtrader", 20, false), receive, change];
+
+        let (_, picked) = classify_outputs(&outputs, &[payment("bcrt1qtrader", 20)]);
+        assert_eq!(picked, Some(&outputs[2]));
+
+        // Without the flag (older nodes), the first unclaimed Miner output is used
+        let unflagged: Vec<OutputInfo> = outputs
+            .iter()
+            .cloned()
+            .map(|o| OutputInfo {
+                is_change: None,
+                ..o
+            })
+            .collect();
+        let (_, picked) = classify_outputs(&unflagged, &[payment("bcrt1qtrader", 20)]);
+        assert_eq!(picked, Some(&unflagged[1]));
+    }
+
+    #[test]
+    fn change_at_the_trader_address_is_reclassified_by_ownership() {
+        let output = |address: &str, btc: f64, miner_owned: bool| OutputInfo {
+            address: address.to_owned(),
+            value: Amount::from_btc(btc).unwrap(),
+            miner_owned,
+            is_change: None,
+        };
+        // The Trader address is (wrongly) also a Miner address, and Core put
+        // change on the same script
+        let outputs = vec![
+            output("bcrt1qshared", 20.0, true),
+            output("bcrt1qshared", 29.9999, true),
+        ];
+        let (matched, change) =
+            classify_outputs(&outputs, &[("bcrt1qshared".to_owned(), outputs[0].value)]);
+        assert_eq!(change, Some(&outputs[1]));
+        assert_eq!(
+            change_distinct_from_trader(&outputs, &matched, change),
+            None
+        );
+
+        // Another Miner output at its own address is the real change
+        let mut outputs = outputs;
+        outputs.push(output("bcrt1qchange", 1.0, true));
+        let (matched, change) =
+            classify_outputs(&outputs, &[("bcrt1qshared".to_owned(), outputs[0].value)]);
+        assert_eq!(
+            change_distinct_from_trader(&outputs, &matched, change),
+            Some(&outputs[2])
+        );
+    }
+
+    #[test]
+    fn fee_rate_rank_counts_cheaper_and_pricier_transactions() {
+        assert_eq!(fee_rate_rank(10.0, &[]), (0, 0));
+        assert_eq!(fee_rate_rank(10.0, &[1.0, 5.0, 10.0, 25.0]), (2, 1));
+    }
+
+    #[test]
+    fn spending_a_coinbase_output_resolves_its_prevout() {
+        // The Miner's send spends output 0 of a block reward
+        let vin = vec![input(Some((txid(1), 0)), false)];
+
+        let outpoints = spent_outpoints(&txid(2), &vin).unwrap();
+
+        assert_eq!(
+            outpoints,
+            vec![OutPoint {
+                txid: txid(1),
+                vout: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn coinbase_input_is_an_error_not_a_panic() {
+        let vin = vec![input(None, true)];
+
+        let result = spent_outpoints(&txid(1), &vin);
+
+        assert!(matches!(result, Err(AppError::InvalidState(_))));
+    }
+
+    #[test]
+    fn fee_of_two_input_transaction_counts_both_inputs() {
+        // 50 + 12.5 BTC in, 60 BTC to the Trader and 2.4999 BTC change
+        let inputs = [Amount::from_int_btc(50), Amount::from_sat(1_250_000_000)];
+        let outputs = [Amount::from_int_btc(60), Amount::from_sat(249_990_000)];
+
+        assert_eq!(
+            transaction_fee(&inputs, &outputs),
+            Some(Amount::from_sat(10_000))
+        );
+    }
+
+    #[test]
+    fn extracted_fee_is_what_the_mocked_inputs_hold_beyond_the_outputs() {
+        use crate::node::mock::{block, raw_tx, MockNode};
+        use crate::node::AddressInfo;
+        use bitcoincore_rpc::bitcoin::absolute::LockTime;
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+        use bitcoincore_rpc::bitcoin::transaction::Version;
+        use bitcoincore_rpc::bitcoin::{Script, ScriptBuf, Sequence, TxIn, Witness};
+
+        let address = |byte: u8| Address::p2wsh(Script::from_bytes(&[byte]), Network::Regtest);
+        let (miner, trader, change) = (address(1), address(2), address(3));
+        let tx = |inputs: &[OutPoint], outputs: &[(&Address, Amount)]| Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|&previous_output| TxIn {
+                    previous_output,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(address, value)| TxOut {
+                    value: *value,
+                    script_pubkey: address.script_pubkey(),
+                })
+                .collect(),
+        };
+
+        // Two earlier Miner outputs, 30 and 5 BTC, confirmed at height 10
+        let funding = OutPoint::new(txid(7), 0);
+        let prev = [
+            tx(&[funding], &[(&miner, Amount::from_int_btc(30))]),
+            tx(
+                &[funding],
+                &[
+                    (&trader, Amount::from_int_btc(1)),
+                    (&miner, Amount::from_int_btc(5)),
+                ],
+            ),
+        ];
+        // Spent together: 20 BTC to the Trader, 14.9999 BTC change
+        let spend = tx(
+            &[
+                OutPoint::new(prev[0].txid(), 0),
+                OutPoint::new(prev[1].txid(), 1),
+            ],
+            &[
+                (&trader, Amount::from_int_btc(20)),
+                (&change, Amount::from_sat(1_499_990_000)),
+            ],
+        );
+        let (funded_in, spent_in) = (
+            BlockHash::from_byte_array([10; 32]),
+            BlockHash::from_byte_array([120; 32]),
+        );
+
+        let mut node = MockNode::default();
+        for prev in &prev {
+            node.raw_transactions
+                .insert(prev.txid(), raw_tx(prev, Some(funded_in)));
+        }
+        node.raw_transactions
+            .insert(spend.txid(), raw_tx(&spend, Some(spent_in)));
+        node.blocks.insert(funded_in, block(10, &[], None));
+        node.blocks
+            .insert(spent_in, block(120, &[spend.txid()], None));
+        for (address, ischange) in [(&miner, false), (&change, true)] {
+            let info = AddressInfo {
+                ismine: true,
+                iswatchonly: false,
+                ischange: Some(ischange),
+            };
+            node.addresses.insert(address.to_string(), info);
+        }
+
+        let payments = [Payment {
+            address: trader.clone(),
+            amount: Amount::from_int_btc(20),
+        }];
+        let details = extract_tx_details(
+            &node,
+            &spend.txid(),
+            Some(&spent_in),
+            &payments,
+            Network::Regtest,
+        )
+        .unwrap();
+
+        assert_eq!(details.fee, Amount::from_sat(10_000));
+        assert_eq!(details.miner_input_address, miner.to_string());
+        assert_eq!(details.miner_input_amount, Amount::from_int_btc(30));
+        assert_eq!(details.trader_output_address, trader.to_string());
+        assert_eq!(details.trader_output_amount, Amount::from_int_btc(20));
+        assert_eq!(details.miner_change_address, change.to_string());
+        assert_eq!(details.block_height, Some(120));
+        assert_eq!(details.input_confirmations, Some(110));
+    }
+
+    #[test]
+    fn segwit_transaction_vsize_is_below_its_size() {
+        use bitcoincore_rpc::bitcoin::absolute::LockTime;
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+        use bitcoincore_rpc::bitcoin::transaction::Version;
+        use bitcoincore_rpc::bitcoin::{ScriptBuf, Sequence, TxIn, TxOut, WPubkeyHash, Witness};
+
+        // One P2WPKH spend: a ~72-byte signature and 33-byte key in the witness
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::from_slice(&[vec![0u8; 72], vec![2u8; 33]]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_int_btc(20),
+                script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+            }],
+        };
+
+        let sizes = tx_sizes(&tx);
+
+        assert!(sizes.vsize < sizes.size);
+        assert_eq!(sizes.vsize, sizes.weight.div_ceil(4));
+    }
+
+    #[test]
+    fn change_equal_to_send_amount_is_not_mistaken_for_trader() {
+        // Change listed first, and with exactly the amount sent to the trader
+        let outputs = vec![
+            output("bcrt1qminerchange", 20, true),
+            output("bcrt1qtrader", 20, false),
+        ];
+
+        let (matched, change) = classify_outputs(&outputs, &[payment("bcrt1qtrader", 20)]);
+
+        assert_eq!(matched, vec![Some(&outputs[1])]);
+        assert_eq!(change, Some(&outputs[0]));
+    }
+
+    #[test]
+    fn extra_outputs_not_owned_by_miner_are_not_change() {
+        let outputs = vec![
+            output("bcrt1qtrader", 20, false),
+            output("bcrt1qsomeoneelse", 5, false),
+            output("bcrt1qminerchange", 25, true),
+        ];
+
+        let (matched, change) = classify_outputs(&outputs, &[payment("bcrt1qtrader", 20)]);
+
+        assert_eq!(matched, vec![Some(&outputs[0])]);
+        assert_eq!(change, Some(&outputs[2]));
+    }
+
+    #[test]
+    fn recipient_owned_by_miner_is_not_taken_as_change() {
+        // The second recipient is one of the Miner's own addresses
+        let outputs = vec![
+            output("bcrt1qminerown", 5, true),
+            output("bcrt1qminerchange", 25, true),
+            output("bcrt1qtrader", 20, false),
+        ];
+        let payments = [payment("bcrt1qtrader", 20), payment("bcrt1qminerown", 5)];
+
+        let (matched, change) = classify_outputs(&outputs, &payments);
+
+        assert_eq!(matched, vec![Some(&outputs[2]), Some(&outputs[0])]);
+        assert_eq!(change, Some(&outputs[1]));
+    }
+
+    #[test]
+    fn three_output_transaction_finds_change_by_ownership() {
+        // Trader, an extra recipient and change, where the change has the same
+        // amount as the recipient and neither of them is listed last
+        let outputs = vec![
+            output("bcrt1qminerchange", 7, true),
+            output("bcrt1qrecipient", 7, false),
+            output("bcrt1qtrader", 20, false),
+        ];
+        let payments = [payment("bcrt1qtrader", 20), payment("bcrt1qrecipient", 7)];
+
+        let (matched, change) = classify_outputs(&outputs, &payments);
+
+        assert_eq!(matched, vec![Some(&outputs[2]), Some(&outputs[1])]);
+        assert_eq!(change, Some(&outputs[0]));
+    }
+}
@@ -0,0 +1,702 @@
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::bip32::{Xpriv, Xpub};
+use bitcoincore_rpc::bitcoin::{Address, Amount, Network, Txid};
+use bitcoincore_rpc::jsonrpc;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::error::{AppError, Result};
+use crate::node::Node;
+use crate::report::{format_amount, Unit};
+
+// Create the wallet `name`, or load it if it already exists (see
+// `create_or_load`). `descriptors` picks a descriptor (`Some(true)`) or legacy
+// (`Some(false)`) wallet; `None` leaves the choice to the node's default.
+pub fn create_or_load_wallet(rpc: &Node, name: &str, descriptors: Option<bool>) -> Result<()> {
+    create_or_load(rpc, name, false, descriptors)
+}
+
+// Create (or load) the watch-only descriptor wallet `name`: no private keys and
+// no descriptors until `import_watch_only_xpub` adds them.
+pub fn create_or_load_watch_only_wallet(rpc: &Node, name: &str) -> Result<()> {
+    create_or_load(rpc, name, true, Some(true))
+}
+
+// Where a wallet is, as far as the node is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletState {
+    // Listed by `listwallets`: ready to use.
+    Loaded,
+    // Listed by `listwalletdir` only: exists on disk but must be loaded.
+    OnDisk,
+    // Neither: has to be created.
+    Missing,
+}
+
+// Classify `name` given the node's loaded wallets and the wallets in its
+// wallet directory.
+pub fn wallet_state(name: &str, loaded: &[String], on_disk: &[String]) -> WalletState {
+    if loaded.iter().any(|w| w == name) {
+        WalletState::Loaded
+    } else if on_disk.iter().any(|w| w == name) {
+        WalletState::OnDisk
+    } else {
+        WalletState::Missing
+    }
+}
+
+// Core's RPC_WALLET_ERROR, returned by `createwallet` for a wallet that
+// already exists on disk (and, before v22, by `loadwallet` for one that is
+// already loaded).
+const RPC_WALLET_ERROR: i32 = -4;
+// Core's RPC_WALLET_ALREADY_LOADED (v22 and later).
+const RPC_WALLET_ALREADY_LOADED: i32 = -35;
+
+// How another instance beat us to creating or loading a wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LostRace {
+    // The wallet is loaded already, so there is nothing left to do.
+    AlreadyLoaded,
+    // The wallet was created meanwhile and only needs loading.
+    AlreadyExists,
+}
+
+// The wallet RPC an error came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WalletCall {
+    Create,
+    Load,
+}
+
+// Whether `e` from `call` only means someone else created or loaded the wallet
+// first; any other error is a genuine failure. Decided by the error code: -35
+// is a loaded wallet, and -4 from `createwallet` one that exists (a genuine
+// create failure then surfaces from the `loadwallet` that follows). Only a -4
+// from `loadwallet`, which pre-v22 nodes return for a loaded wallet among other
+// failures, falls back to the message.
+fn lost_race(e: &AppError, call: WalletCall) -> Option<LostRace> {
+    let AppError::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error))) = e else {
+        return None;
+    };
+    match (rpc_error.code, call) {
+        (RPC_WALLET_ALREADY_LOADED, _) => Some(LostRace::AlreadyLoaded),
+        (RPC_WALLET_ERROR, WalletCall::Create) => Some(LostRace::AlreadyExists),
+        (RPC_WALLET_ERROR, WalletCall::Load) if rpc_error.message.contains("already loaded") => {
+            Some(LostRace::AlreadyLoaded)
+        }
+        _ => None,
+    }
+}
+
+// `loadwallet`, treating a wallet that got loaded meanwhile as success.
+fn load(rpc: &Node, name: &str) -> Result<()> {
+    match rpc.load_wallet(name) {
+        Ok(()) => info!("Loaded {} wallet from the wallet directory", name),
+        Err(e) if lost_race(&e, WalletCall::Load) == Some(LostRace::AlreadyLoaded) => {
+            info!("{} wallet was loaded meanwhile", name)
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+// The wallet `name` inside `dir` (--wallet-dir), as the path `loadwallet` and
+// `createwallet` accept in place of a name. The wallet then goes by that path,
+// including in its /wallet/<path> RPC URL. `dir` must exist.
+pub fn wallet_path(dir: &Path, name: &str) -> Result<String> {
+    if !dir.is_dir() {
+        return Err(AppError::InvalidArgument(format!(
+            "wallet directory {} does not exist",
+            dir.display()
+        )));
+    }
+    // Absolute, so the node does not resolve it against its own directory
+    let path = dir.canonicalize()?.join(name);
+    info!("Using the {} wallet at {}", name, path.display());
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// Percent-encode a wallet name (which may be a path) for the /wallet/<name>
+// part of an RPC URL, leaving only RFC 3986 unreserved characters as they are.
+pub fn url_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Make the wallet `name` available: nothing to do when it is loaded already,
+// `loadwallet` when it only exists on disk, `createwallet` otherwise. Which
+// case applies is decided up front with `listwallets`/`listwalletdir`. Another
+// instance starting at the same time may still create or load the wallet in
+// between; those errors (see `lost_race`) count as success, and every other
+// error is returned.
+fn create_or_load(
+    rpc: &Node,
+    name: &str,
+    watch_only: bool,
+    descriptors: Option<bool>,
+) -> Result<()> {
+    let mut state = wallet_state(name, &rpc.list_wallets()?, &rpc.list_wallet_dir()?);
+    // listwalletdir only covers the node's wallet directory; a --wallet-dir
+    // wallet is on disk if its path exists
+    if state == WalletState::Missing && Path::new(name).is_absolute() && Path::new(name).exists() {
+        state = WalletState::OnDisk;
+    }
+    match state {
+        WalletState::Loaded => info!("{} wallet is already loaded", name),
+        WalletState::OnDisk => load(rpc, name)?,
+        WalletState::Missing => match rpc.create_wallet(name, watch_only, descriptors) {
+            Ok(()) => info!("Created {} wallet", name),
+            Err(e) => match lost_race(&e, WalletCall::Create) {
+                Some(LostRace::AlreadyLoaded) => {
+                    info!("{} wallet was created and loaded meanwhile", name)
+                }
+                Some(LostRace::AlreadyExists) => load(rpc, name)?,
+                None => return Err(e),
+            },
+        },
+    }
+    Ok(())
+}
+
+// Whether the wallet behind `rpc` is a descriptor wallet, per `getwalletinfo`.
+// The typed `GetWalletInfoResult` has no `descriptors` field, and legacy-only
+// nodes omit it.
+fn is_descriptor_wallet(rpc: &Node) -> Result<bool> {
+    #[derive(Deserialize)]
+    struct WalletInfo {
+        #[serde(default)]
+        descriptors: bool,
+    }
+    let info: WalletInfo = rpc.wallet_info()?;
+    Ok(info.descriptors)
+}
+
+// Warn when an existing wallet is not of the requested descriptor/legacy type,
+// since it was created earlier and cannot be converted here.
+pub fn check_wallet_type(rpc: &Node, name: &str, descriptors: Option<bool>) -> Result<()> {
+    let Some(wanted) = descriptors else {
+        return Ok(());
+    };
+    let actual = is_descriptor_wallet(rpc)?;
+    if actual != wanted {
+        let kind = |d: bool| if d { "descriptor" } else { "legacy" };
+        warn!(
+            "{} wallet is a {} wallet, but a {} wallet was requested; using it as is",
+            name,
+            kind(actual),
+            kind(wanted)
+        );
+    }
+    Ok(())
+}
+
+// File name of the backup of wallet `name`. A --wallet-dir wallet is named by
+// its path, so only the last part of it is used.
+fn backup_file_name(name: &str) -> String {
+    let base = Path::new(name)
+        .file_name()
+        .map_or_else(|| name.into(), |n| n.to_string_lossy());
+    format!("{}.bak", base)
+}
+
+// Back up the wallet `name` behind `rpc` into `dir` with `backupwallet`,
+// returning the backup path, or `None` when it was skipped. The node writes the
+// file, so `dir` must be reachable from the node (not only from this process).
+// A descriptor wallet whose backup fails is skipped with a warning, since
+// `backupwallet` support differs for descriptor wallets across Core versions.
+pub fn backup_wallet(rpc: &Node, name: &str, dir: &Path) -> Result<Option<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let path = fs::canonicalize(dir)?.join(backup_file_name(name));
+    match rpc.backup_wallet(&path) {
+        Ok(()) => {
+            info!("Backed up {} wallet to {}", name, path.display());
+            Ok(Some(path))
+        }
+        Err(e) if is_descriptor_wallet(rpc)? => {
+            warn!(
+                "Skipping backup of descriptor wallet {}: backupwallet failed: {}",
+                name, e
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Rescan the chain from `from_height` (the genesis block if `None`) to the tip
+// for transactions of the wallet behind `rpc`, logging the scanned range and
+// how long it took. A pruned node has discarded old blocks, so it is refused.
+pub fn rescan_wallet(rpc: &Node, name: &str, from_height: Option<u32>) -> Result<()> {
+    if rpc.blockchain_info()?.pruned {
+        return Err(AppError::InvalidState(
+            "cannot rescan on a pruned node: old blocks are no longer available".to_owned(),
+        ));
+    }
+
+    #[derive(Deserialize)]
+    struct RescanResult {
+        start_height: u64,
+        stop_height: Option<u64>,
+    }
+    let started = Instant::now();
+    let result: RescanResult = rpc.rescan_blockchain(from_height.unwrap_or(0))?;
+    info!(
+        "Rescanned {} wallet over blocks {}..={} in {:.2?}",
+        name,
+        result.start_height,
+        result
+            .stop_height
+            .map_or_else(|| "tip".to_owned(), |h| h.to_string()),
+        started.elapsed()
+    );
+    Ok(())
+}
+
+// Fixed BIP32 seeds for `--deterministic`. The wallets import
+//
+//     wpkh(<master key of the seed>/84h/1h/0h/0/*)   receive (external)
+//     wpkh(<master key of the seed>/84h/1h/0h/1/*)   change (internal)
+//
+// and the mining and Trader addresses are index 0 of the receive descriptors,
+// so they are the same on every run and every node. Test keys only: anyone can
+// spend coins sent to them.
+pub const MINER_SEED: [u8; 32] = [0x01; 32];
+pub const TRADER_SEED: [u8; 32] = [0x02; 32];
+
+// Import the fixed receive and change descriptors derived from `seed` into the
+// (descriptor) wallet behind `rpc` as its active ones. Re-importing on a later
+// run is harmless. Returns the public receive descriptor for `derive_address`.
+pub fn import_deterministic_descriptors(
+    rpc: &Node,
+    name: &str,
+    seed: &[u8],
+    network: Network,
+) -> Result<String> {
+    let master = Xpriv::new_master(network, seed)
+        .map_err(|e| AppError::InvalidState(format!("cannot derive {} key: {}", name, e)))?;
+    let receive = import_active_descriptors(
+        rpc,
+        name,
+        &format!("wpkh({}/84h/1h/0h/0/*)", master),
+        &format!("wpkh({}/84h/1h/0h/1/*)", master),
+    )?;
+    info!(
+        "Imported deterministic descriptors into the {} wallet",
+        name
+    );
+    Ok(receive)
+}
+
+// Import `wpkh(<xpub>/0/*)` (receive) and `wpkh(<xpub>/1/*)` (change) into the
+// watch-only wallet behind `rpc`. A descriptor wallet created without private
+// keys only ever watches what it imports, so no separate watch-only flag is
+// needed. Returns the receive descriptor for `derive_address`.
+pub fn import_watch_only_xpub(rpc: &Node, name: &str, xpub: &Xpub) -> Result<String> {
+    let receive = import_active_descriptors(
+        rpc,
+        name,
+        &format!("wpkh({}/0/*)", xpub),
+        &format!("wpkh({}/1/*)", xpub),
+    )?;
+    info!(
+        "Imported watch-only xpub descriptors into the {} wallet",
+        name
+    );
+    Ok(receive)
+}
+
+// Import `receive` and `change` (descriptors without checksum) as the active
+// external and internal descriptors of the wallet `name` behind `rpc`.
+// Returns the receive descriptor as normalised by `getdescriptorinfo`.
+fn import_active_descriptors(
+    rpc: &Node,
+    name: &str,
+    receive: &str,
+    change: &str,
+) -> Result<String> {
+    #[derive(Deserialize)]
+    struct DescriptorInfo {
+        descriptor: String,
+        checksum: String,
+    }
+    #[derive(Deserialize)]
+    struct ImportResult {
+        success: bool,
+        #[serde(default)]
+        error: Option<serde_json::Value>,
+    }
+
+    let mut normalised = String::new();
+    let mut requests = Vec::new();
+    for (desc, internal) in [(receive, false), (change, true)] {
+        let info: DescriptorInfo = rpc.descriptor_info(desc)?;
+        if !internal {
+            normalised = info.descriptor;
+        }
+        requests.push(json!({
+            "desc": format!("{}#{}", desc, info.checksum),
+            "active": true,
+            "internal": internal,
+            "range": [0, 999],
+            "timestamp": "now",
+        }));
+    }
+
+    let results: Vec<ImportResult> = rpc.import_descriptors(&requests)?;
+    if let Some(failed) = results.iter().find(|r| !r.success) {
+        return Err(AppError::InvalidState(format!(
+            "importing descriptors into the {} wallet failed: {}",
+            name,
+            failed.error.clone().unwrap_or_default()
+        )));
+    }
+    Ok(normalised)
+}
+
+// Address at `index` of the public `descriptor`, labelled `label` in the
+// wallet behind `rpc` like a `getnewaddress` address would be.
+pub fn derive_address(
+    rpc: &Node,
+    descriptor: &str,
+    index: u32,
+    label: &str,
+) -> Result<Address<NetworkUnchecked>> {
+    let addresses = rpc.derive_addresses(descriptor, index)?;
+    let address = addresses.into_iter().next().ok_or_else(|| {
+        AppError::InvalidState(format!(
+            "deriveaddresses returned nothing for {}",
+            descriptor
+        ))
+    })?;
+    rpc.set_label(&address.clone().assume_checked().to_string(), label)?;
+    Ok(address)
+}
+
+// Core's RPC_WALLET_INVALID_LABEL_NAME, which `getaddressesbylabel` also
+// returns for a label with no addresses.
+const RPC_WALLET_INVALID_LABEL_NAME: i32 = -11;
+
+// An address the wallet behind `rpc` already has under `label`, or `None` if
+// there is none. With several, the lexicographically first is picked so the
+// choice is stable across runs (`getaddressesbylabel` returns an unordered map).
+pub fn address_by_label(rpc: &Node, label: &str) -> Result<Option<Address<NetworkUnchecked>>> {
+    let addresses = match rpc.addresses_by_label(label) {
+        Ok(addresses) => addresses,
+        Err(AppError::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e))))
+            if e.code == RPC_WALLET_INVALID_LABEL_NAME =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    Ok(addresses
+        .into_keys()
+        .min_by_key(|address| address.clone().assume_checked().to_string()))
+}
+
+// Every label of the wallet behind `rpc` (`listlabels`) with its addresses
+// (`getaddressesbylabel`), both sorted so the listing is stable across runs.
+pub fn list_labels(rpc: &Node) -> Result<Vec<(String, Vec<String>)>> {
+    let mut labels = rpc.labels()?;
+    labels.sort();
+    let mut listing = Vec::with_capacity(labels.len());
+    for label in labels {
+        let mut addresses: Vec<String> = rpc
+            .addresses_by_label(&label)?
+            .into_keys()
+            .map(|address| address.assume_checked().to_string())
+            .collect();
+        addresses.sort();
+        listing.push((label, addresses));
+    }
+    Ok(listing)
+}
+
+// Print the labels of wallet `name` and the addresses under each of them.
+pub fn print_labels(name: &str, rpc: &Node) -> Result<()> {
+    let labels = list_labels(rpc)?;
+    say!("{} wallet: {} label(s)", name, labels.len());
+    for (label, addresses) in labels {
+        let shown = if label.is_empty() {
+            "(no label)"
+        } else {
+            &label
+        };
+        say!("  {} ({} address(es))", shown, addresses.len());
+        for address in addresses {
+            say!("    {}", address);
+        }
+    }
+    Ok(())
+}
+
+// Unload each named wallet from the node. Failures are only reported, since
+// this runs during cleanup where there is nothing better to do with them.
+pub fn unload_wallets(rpc: &Node, names: &[&str]) {
+    for name in names {
+        match rpc.unload_wallet(name) {
+            Ok(()) => info!("Unloaded {} wallet", name),
+            Err(e) => warn!("Could not unload {} wallet: {}", name, e),
+        }
+    }
+}
+
+// Unloads the given wallets when dropped, so cleanup also happens when `main`
+// returns early with an error. Unloading happens at most once, so an explicit
+// `unload` (e.g. after Ctrl-C) is not repeated on drop.
+pub struct UnloadGuard<'a> {
+    rpc: &'a Node,
+    names: Vec<&'a str>,
+    unloaded: bool,
+}
+
+impl<'a> UnloadGuard<'a> {
+    pub fn new(rpc: &'a Node, names: &[&'a str]) -> Self {
+        UnloadGuard {
+            rpc,
+            names: names.to_vec(),
+            unloaded: false,
+        }
+    }
+
+    pub fn unload(&mut self) {
+        if !self.unloaded {
+            self.unloaded = true;
+            unload_wallets(self.rpc, &self.names);
+        }
+    }
+}
+
+impl Drop for UnloadGuard<'_> {
+    fn drop(&mut self) {
+        self.unload();
+    }
+}
+
+// Print confirmed, unconfirmed and immature balances of the named `wallets` as
+// a small table in `unit`. Uses `getbalances` so immature coinbase rewards show
+// up as well.
+pub fn report_balances(wallets: &[(&str, &Node)], unit: Unit) -> Result<()> {
+    let width = wallets
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Wallet".len());
+    say!(
+        "{:<width$} {:>16} {:>16} {:>16}  ({})",
+        "Wallet",
+        "Confirmed",
+        "Unconfirmed",
+        "Immature",
+        unit,
+        width = width
+    );
+    for (name, rpc) in wallets {
+        let balances = rpc.balances()?.mine;
+        say!(
+            "{:<width$} {:>16} {:>16} {:>16}",
+            name,
+            format_amount(balances.trusted, unit),
+            format_amount(balances.untrusted_pending, unit),
+            format_amount(balances.immature, unit),
+            width = width
+        );
+    }
+    Ok(())
+}
+
+// A spendable coin of a wallet, as reported by `listunspent`.
+#[derive(Debug, Clone)]
+pub struct Utxo {
+    pub txid: Txid,
+    pub vout: u32,
+    pub amount: Amount,
+    pub confirmations: u32,
+}
+
+// List the wallet's spendable UTXOs with at least `min_conf` confirmations,
+// largest first. Immature coinbase outputs are not listed by Core, which is
+// usually why a freshly mined wallet has fewer coins than blocks.
+pub fn list_utxos(rpc: &Node, min_conf: u32) -> Result<Vec<Utxo>> {
+    let mut utxos: Vec<Utxo> = rpc
+        .list_unspent(Some(min_conf as usize))?
+        .into_iter()
+        .filter(|u| u.spendable)
+        .map(|u| Utxo {
+            txid: u.txid,
+            vout: u.vout,
+            amount: u.amount,
+            confirmations: u.confirmations,
+        })
+        .collect();
+    utxos.sort_by_key(|u| std::cmp::Reverse(u.amount));
+    Ok(utxos)
+}
+
+// Print `utxos` as a table with amounts in `unit`, followed by their total.
+pub fn print_utxos(utxos: &[Utxo], unit: Unit) {
+    say!(
+        "{:>16} {:>6} {:>6}  txid  ({})",
+        "Amount",
+        "Vout",
+        "Confs",
+        unit
+    );
+    for utxo in utxos {
+        say!(
+            "{:>16} {:>6} {:>6}  {}",
+            format_amount(utxo.amount, unit),
+            utxo.vout,
+            utxo.confirmations,
+            utxo.txid
+        );
+    }
+    let total: Amount = utxos.iter().map(|u| u.amount).sum();
+    say!(
+        "{} UTXO(s), {} {} in total",
+        utxos.len(),
+        format_amount(total, unit),
+        unit
+    );
+}
+
+// Core's RPC_WALLET_UNLOCK_NEEDED: the wallet is encrypted and locked.
+const RPC_WALLET_UNLOCK_NEEDED: i32 = -13;
+// Core's RPC_WALLET_INSUFFICIENT_FUNDS.
+const RPC_WALLET_INSUFFICIENT_FUNDS: i32 = -6;
+
+// Whether `e` is the node refusing to sign because the wallet is locked.
+pub fn is_locked_error(e: &bitcoincore_rpc::Error) -> bool {
+    matches!(
+        e,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error))
+            if rpc_error.code == RPC_WALLET_UNLOCK_NEEDED
+    )
+}
+
+// Whether `e` is the node refusing a send the wallet cannot fund.
+pub fn is_insufficient_funds_error(e: &bitcoincore_rpc::Error) -> bool {
+    matches!(
+        e,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error))
+            if rpc_error.code == RPC_WALLET_INSUFFICIENT_FUNDS
+    )
+}
+
+// Keeps an encrypted wallet unlocked with `walletpassphrase` and locks it again
+// with `walletlock` when dropped, so it is not left unlocked after an error.
+pub struct UnlockGuard<'a> {
+    rpc: &'a Node,
+    name: &'a str,
+}
+
+impl<'a> UnlockGuard<'a> {
+    // Unlock the wallet behind `rpc` for at most `timeout_secs` seconds.
+    pub fn new(rpc: &'a Node, name: &'a str, passphrase: &str, timeout_secs: u64) -> Result<Self> {
+        rpc.wallet_passphrase(passphrase, timeout_secs)?;
+        info!("Unlocked {} wallet for up to {}s", name, timeout_secs);
+        Ok(UnlockGuard { rpc, name })
+    }
+}
+
+impl Drop for UnlockGuard<'_> {
+    fn drop(&mut self) {
+        match self.rpc.wallet_lock() {
+            Ok(()) => info!("Locked {} wallet again", self.name),
+            Err(e) => warn!("Could not lock {} wallet: {}", self.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_error(code: i32, message: &str) -> AppError {
+        AppError::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(
+            jsonrpc::error::RpcError {
+                code,
+                message: message.to_owned(),
+                data: None,
+            },
+        )))
+    }
+
+    #[test]
+    fn wallet_loaded_by_another_instance_counts_as_success() {
+        // v22+: loadwallet on a wallet that another instance already loaded
+        let e = rpc_error(
+            RPC_WALLET_ALREADY_LOADED,
+            "Wallet \"Miner\" is already loaded.",
+        );
+        assert_eq!(
+            lost_race(&e, WalletCall::Load),
+            Some(LostRace::AlreadyLoaded)
+        );
+        // v0.21: the same case as a generic wallet error
+        let e = rpc_error(
+            RPC_WALLET_ERROR,
+            "Wallet file verification failed. Data file is already loaded.",
+        );
+        assert_eq!(
+            lost_race(&e, WalletCall::Load),
+            Some(LostRace::AlreadyLoaded)
+        );
+        // createwallet after another instance created it: load it instead,
+        // whatever the message says
+        for message in [
+            "Wallet file verification failed. Failed to create database path. \
+             Database already exists.",
+            "A wallet with this name exists",
+        ] {
+            let e = rpc_error(RPC_WALLET_ERROR, message);
+            assert_eq!(
+                lost_race(&e, WalletCall::Create),
+                Some(LostRace::AlreadyExists)
+            );
+        }
+    }
+
+    #[test]
+    fn wallet_paths_are_percent_encoded_in_rpc_urls() {
+        assert_eq!(url_encode("Miner"), "Miner");
+        assert_eq!(
+            url_encode("/srv/wallets/Trader Two"),
+            "%2Fsrv%2Fwallets%2FTrader%20Two"
+        );
+    }
+
+    #[test]
+    fn wallet_dir_backups_are_named_after_the_wallet_not_its_path() {
+        assert_eq!(backup_file_name("Miner"), "Miner.bak");
+        assert_eq!(backup_file_name("/srv/wallets/Miner"), "Miner.bak");
+    }
+
+    #[test]
+    fn other_wallet_errors_are_genuine_failures() {
+        assert_eq!(
+            lost_race(
+                &rpc_error(RPC_WALLET_ERROR, "Wallet file not found"),
+                WalletCall::Load
+            ),
+            None
+        );
+        for call in [WalletCall::Create, WalletCall::Load] {
+            assert_eq!(
+                lost_race(&rpc_error(-18, "Requested wallet does not exist"), call),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// End-to-end check of the full Miner -> Trader flow against a live regtest node.
+//
+// Ignored by default because it needs a running `bitcoind -regtest`. Start one
+// (e.g. `docker compose up -d` from the repo root) and run:
+//
+//     cargo test -- --ignored
+//
+// To use an existing node instead, point the binary at it with the same
+// environment variables it reads at runtime, for example:
+//
+//     BITCOIN_RPC_URL=http://127.0.0.1:18443 BITCOIN_RPC_USER=alice \
+//         BITCOIN_RPC_PASS=password cargo test -- --ignored
+
+use std::fs;
+use std::process::Command;
+
+#[test]
+#[ignore = "requires a running regtest node"]
+fn full_flow_writes_complete_report() {
+    let out_dir = std::env::temp_dir().join("rust-capstone-regtest-flow");
+    let out_path = out_dir.join("out.txt");
+    let _ = fs::remove_file(&out_path);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rust"))
+        .arg("--out")
+        .arg(&out_path)
+        .status()
+        .expect("failed to launch the binary");
+    assert!(status.success(), "binary exited with {}", status);
+
+    let contents = fs::read_to_string(&out_path).expect("out.txt was not written");
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 10, "unexpected out.txt:\n{}", contents);
+    for (i, line) in lines.iter().enumerate() {
+        assert!(
+            !line.trim().is_empty(),
+            "line {} of out.txt is empty",
+            i + 1
+        );
+    }
+
+    let fee: f64 = lines[7].trim().parse().expect("fee is not a number");
+    assert!(fee > 0.0, "fee should be positive, got {}", fee);
+
+    // The Miner input is a coinbase reward, which must have matured
+    let json =
+        fs::read_to_string(out_path.with_extension("json")).expect("out.json was not written");
+    let report: serde_json::Value = serde_json::from_str(&json).expect("out.json is not JSON");
+    let confirmations = report["input_confirmations"]
+        .as_u64()
+        .expect("input_confirmations is missing");
+    assert!(
+        confirmations >= 100,
+        "coinbase input spent with only {} confirmations",
+        confirmations
+    );
+}
+
+#[test]
+#[ignore = "requires a running regtest node"]
+fn no_confirm_reports_a_mempool_transaction() {
+    let out_dir = std::env::temp_dir().join("rust-capstone-regtest-no-confirm");
+    let out_path = out_dir.join("out.txt");
+    let _ = fs::remove_file(&out_path);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rust"))
+        .arg("--no-confirm")
+        .arg("--out")
+        .arg(&out_path)
+        .status()
+        .expect("failed to launch the binary");
+    assert!(status.success(), "binary exited with {}", status);
+
+    let contents = fs::read_to_string(&out_path).expect("out.txt was not written");
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 10, "unexpected out.txt:\n{}", contents);
+    assert_eq!(lines[8], "-1");
+    assert_eq!(lines[9], "unconfirmed");
+}